@@ -9,6 +9,18 @@ cp /sys/fs/selinux/policy "${log_dir}"/sepolicy.orig
 "${mod_dir}"/msd-tool."$(getprop ro.product.cpu.abi)" sepatch -ST
 cp /sys/fs/selinux/policy "${log_dir}"/sepolicy.patched
 
+# The daemon can only write its saved configuration (--persist-config) to a
+# directory with this label. The daemon opens the directory as root, but
+# accesses it as the system user afterwards.
+
+header Preparing daemon data directory
+
+mkdir -p /data/adb/msd
+chown -R system:system /data/adb/msd
+chmod 700 /data/adb/msd
+chcon -R u:object_r:msd_daemon_data_file:s0 /data/adb/msd
+ls -ldZ /data/adb/msd
+
 # Android's SELinux implementation cannot load seapp_contexts files from a
 # directory, so the original file must be edited and multiple modules may want
 # to do so. Due to Magisk/KernelSU's behavior of running scripts for all modules
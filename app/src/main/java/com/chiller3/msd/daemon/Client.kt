@@ -54,7 +54,8 @@ class Client : Closeable {
         try {
             for (device in devices) {
                 try {
-                    val fd = context.contentResolver.openFileDescriptor(device.uri, "r")
+                    val mode = if (device.type == DeviceType.DISK_RW) "rw" else "r"
+                    val fd = context.contentResolver.openFileDescriptor(device.uri, mode)
                         ?: throw IOException("File provider recently crashed for ${device.uri}")
                     openFds.add(fd)
                 } catch (e: Exception) {
@@ -91,7 +92,10 @@ class Client : Closeable {
         val response = Response.fromSocket(socket)
         when (response.message) {
             is ErrorResponse -> throw ClientException(response.message.message)
-            is GetMassStorageResponse -> return response.message.devices.map {
+            // Media that was ejected by the host is no longer exported.
+            is GetMassStorageResponse -> return response.message.devices.filter {
+                it.state == LunState.ACTIVE
+            }.map {
                 val type = if (it.cdrom) {
                     DeviceType.CDROM
                 } else if (it.ro) {
@@ -45,6 +45,18 @@ private fun InputStream.readShortLe(): Short {
     return ByteBuffer.wrap(buf).order(ByteOrder.LITTLE_ENDIAN).short
 }
 
+private fun InputStream.readIntLe(): Int {
+    val buf = ByteArray(4)
+    readFully(buf, 0, 4)
+    return ByteBuffer.wrap(buf).order(ByteOrder.LITTLE_ENDIAN).int
+}
+
+private fun InputStream.readLongLe(): Long {
+    val buf = ByteArray(8)
+    readFully(buf, 0, 8)
+    return ByteBuffer.wrap(buf).order(ByteOrder.LITTLE_ENDIAN).long
+}
+
 private fun InputStream.readData(): ByteArray {
     val size = readShortLe().toInt()
     val buf = ByteArray(size)
@@ -60,6 +72,14 @@ private fun OutputStream.writeShortLe(value: Short) {
     write(ByteBuffer.allocate(2).order(ByteOrder.LITTLE_ENDIAN).putShort(value).array())
 }
 
+private fun OutputStream.writeIntLe(value: Int) {
+    write(ByteBuffer.allocate(4).order(ByteOrder.LITTLE_ENDIAN).putInt(value).array())
+}
+
+private fun OutputStream.writeLongLe(value: Long) {
+    write(ByteBuffer.allocate(8).order(ByteOrder.LITTLE_ENDIAN).putLong(value).array())
+}
+
 private fun OutputStream.writeData(buf: ByteArray) {
     if (buf.size > Short.MAX_VALUE) {
         throw IllegalArgumentException("Data length exceeds u16 bounds")
@@ -69,6 +89,50 @@ private fun OutputStream.writeData(buf: ByteArray) {
     write(buf)
 }
 
+private fun InputStream.readOptionalShortLe(): Short? {
+    val present = readByte().toInt() != 0
+    val value = readShortLe()
+    return if (present) { value } else { null }
+}
+
+private fun InputStream.readOptionalIntLe(): Int? {
+    val present = readByte().toInt() != 0
+    val value = readIntLe()
+    return if (present) { value } else { null }
+}
+
+private fun InputStream.readOptionalLongLe(): Long? {
+    val present = readByte().toInt() != 0
+    val value = readLongLe()
+    return if (present) { value } else { null }
+}
+
+private fun InputStream.readOptionalString(): String? {
+    val present = readByte().toInt() != 0
+    val value = String(readData())
+    return if (present) { value } else { null }
+}
+
+private fun OutputStream.writeOptionalShortLe(value: Short?) {
+    writeByte(if (value != null) { 1 } else { 0 })
+    writeShortLe(value ?: 0)
+}
+
+private fun OutputStream.writeOptionalIntLe(value: Int?) {
+    writeByte(if (value != null) { 1 } else { 0 })
+    writeIntLe(value ?: 0)
+}
+
+private fun OutputStream.writeOptionalLongLe(value: Long?) {
+    writeByte(if (value != null) { 1 } else { 0 })
+    writeLongLe(value ?: 0)
+}
+
+private fun OutputStream.writeOptionalString(value: String?) {
+    writeByte(if (value != null) { 1 } else { 0 })
+    writeData((value ?: "").toByteArray())
+}
+
 private fun LocalSocket.receiveFds(size: Int): Array<FileDescriptor> {
     inputStream.readByte()
 
@@ -85,7 +149,7 @@ private fun LocalSocket.sendFds(fds: Array<FileDescriptor>) {
     outputStream.writeByte(0)
 }
 
-private const val PROTOCOL_VERSION: Byte = 1
+private const val PROTOCOL_VERSION: Byte = 2
 
 fun negotiateProtocol(stream: LocalSocket) {
     stream.outputStream.writeByte(PROTOCOL_VERSION)
@@ -168,15 +232,24 @@ data class GetFunctionsResponse(val functions: Map<String, String>) : ResponseMe
     }
 }
 
-data class MassStorageDevice(val fd: FileDescriptor, val cdrom: Boolean, val ro: Boolean) :
-    ToSocket {
+data class MassStorageDevice(
+    val fd: FileDescriptor,
+    val cdrom: Boolean,
+    val ro: Boolean,
+    val removable: Boolean = true,
+    val nofua: Boolean = false,
+    val inquiryString: String = "",
+) : ToSocket {
     companion object : FromSocket<MassStorageDevice> {
         override fun fromSocket(stream: LocalSocket): MassStorageDevice {
             val fd = stream.receiveFds(1)[0]
             val cdrom = stream.inputStream.readByte().toInt() != 0
             val ro = stream.inputStream.readByte().toInt() != 0
+            val removable = stream.inputStream.readByte().toInt() != 0
+            val nofua = stream.inputStream.readByte().toInt() != 0
+            val inquiryString = String(stream.inputStream.readData())
 
-            return MassStorageDevice(fd, cdrom, ro)
+            return MassStorageDevice(fd, cdrom, ro, removable, nofua, inquiryString)
         }
     }
 
@@ -184,10 +257,104 @@ data class MassStorageDevice(val fd: FileDescriptor, val cdrom: Boolean, val ro:
         stream.sendFds(arrayOf(fd))
         stream.outputStream.writeByte(if (cdrom) { 1 } else { 0 })
         stream.outputStream.writeByte(if (ro) { 1 } else { 0 })
+        stream.outputStream.writeByte(if (removable) { 1 } else { 0 })
+        stream.outputStream.writeByte(if (nofua) { 1 } else { 0 })
+        stream.outputStream.writeData(inquiryString.toByteArray())
     }
 }
 
-data class SetMassStorageRequest(val devices: List<MassStorageDevice>) : RequestMessage, ToSocket {
+sealed interface Lease : ToSocket {
+    data object None : Lease {
+        override fun toSocket(stream: LocalSocket) {
+            stream.outputStream.writeByte(0)
+            stream.outputStream.writeIntLe(0)
+        }
+    }
+
+    data object Connection : Lease {
+        override fun toSocket(stream: LocalSocket) {
+            stream.outputStream.writeByte(1)
+            stream.outputStream.writeIntLe(0)
+        }
+    }
+
+    data class Timeout(val timeoutMs: Int) : Lease {
+        override fun toSocket(stream: LocalSocket) {
+            stream.outputStream.writeByte(2)
+            stream.outputStream.writeIntLe(timeoutMs)
+        }
+    }
+
+    companion object : FromSocket<Lease> {
+        override fun fromSocket(stream: LocalSocket): Lease {
+            val kind = stream.inputStream.readByte()
+            val timeoutMs = stream.inputStream.readIntLe()
+
+            return when (kind.toInt()) {
+                0 -> None
+                1 -> Connection
+                2 -> Timeout(timeoutMs)
+                else -> throw IOException("Invalid lease type: $kind")
+            }
+        }
+    }
+}
+
+data class DescriptorOverrides(
+    val idVendor: Short? = null,
+    val idProduct: Short? = null,
+    val bcdDevice: Short? = null,
+    val manufacturer: String? = null,
+    val product: String? = null,
+    val serialNumber: String? = null,
+) : ToSocket {
+    companion object : FromSocket<DescriptorOverrides> {
+        override fun fromSocket(stream: LocalSocket): DescriptorOverrides {
+            val idVendor = stream.inputStream.readOptionalShortLe()
+            val idProduct = stream.inputStream.readOptionalShortLe()
+            val bcdDevice = stream.inputStream.readOptionalShortLe()
+            val manufacturer = stream.inputStream.readOptionalString()
+            val product = stream.inputStream.readOptionalString()
+            val serialNumber = stream.inputStream.readOptionalString()
+
+            return DescriptorOverrides(
+                idVendor, idProduct, bcdDevice, manufacturer, product, serialNumber)
+        }
+    }
+
+    override fun toSocket(stream: LocalSocket) {
+        stream.outputStream.writeOptionalShortLe(idVendor)
+        stream.outputStream.writeOptionalShortLe(idProduct)
+        stream.outputStream.writeOptionalShortLe(bcdDevice)
+        stream.outputStream.writeOptionalString(manufacturer)
+        stream.outputStream.writeOptionalString(product)
+        stream.outputStream.writeOptionalString(serialNumber)
+    }
+}
+
+enum class SpeedLimit(val id: Byte) {
+    HIGH_SPEED(1),
+    FULL_SPEED(2);
+
+    companion object {
+        fun fromId(id: Byte): SpeedLimit? = if (id == 0.toByte()) {
+            null
+        } else {
+            entries.find { it.id == id } ?: throw IOException("Invalid speed limit: $id")
+        }
+    }
+}
+
+data class SetMassStorageRequest(
+    val devices: List<MassStorageDevice>,
+    val lease: Lease = Lease.None,
+    val force: Boolean = false,
+    val stall: Boolean = true,
+    val descriptors: DescriptorOverrides = DescriptorOverrides(),
+    val maxSpeed: SpeedLimit? = null,
+    val idleTimeoutMs: Int = 0,
+    val oneShot: Int? = null,
+) : RequestMessage, ToSocket {
     companion object : MessageId, FromSocket<SetMassStorageRequest> {
         override val id: Byte = 4
 
@@ -200,7 +367,16 @@ data class SetMassStorageRequest(val devices: List<MassStorageDevice>) : Request
                 devices.add(device)
             }
 
-            return SetMassStorageRequest(devices)
+            val lease = Lease.fromSocket(stream)
+            val force = stream.inputStream.readByte().toInt() != 0
+            val stall = stream.inputStream.readByte().toInt() != 0
+            val descriptors = DescriptorOverrides.fromSocket(stream)
+            val maxSpeed = SpeedLimit.fromId(stream.inputStream.readByte())
+            val idleTimeoutMs = stream.inputStream.readIntLe()
+            val oneShot = stream.inputStream.readOptionalIntLe()
+
+            return SetMassStorageRequest(
+                devices, lease, force, stall, descriptors, maxSpeed, idleTimeoutMs, oneShot)
         }
     }
 
@@ -213,6 +389,14 @@ data class SetMassStorageRequest(val devices: List<MassStorageDevice>) : Request
         for (device in devices) {
             device.toSocket(stream)
         }
+
+        lease.toSocket(stream)
+        stream.outputStream.writeByte(if (force) { 1 } else { 0 })
+        stream.outputStream.writeByte(if (stall) { 1 } else { 0 })
+        descriptors.toSocket(stream)
+        stream.outputStream.writeByte(maxSpeed?.id ?: 0)
+        stream.outputStream.writeIntLe(idleTimeoutMs)
+        stream.outputStream.writeOptionalIntLe(oneShot)
     }
 }
 
@@ -225,22 +409,111 @@ object SetMassStorageResponse : ResponseMessage, MessageId, FromSocket<SetMassSt
     override fun toSocket(stream: LocalSocket) {}
 }
 
-data class ActiveMassStorageDevice(val file: String, val cdrom: Boolean, val ro: Boolean) :
-    ToSocket {
+enum class LunState(val id: Byte) {
+    ACTIVE(0),
+    EJECTED_BY_HOST(1),
+    EJECTED_ON_CHANGE(2);
+
+    companion object {
+        fun fromId(id: Byte): LunState =
+            entries.find { it.id == id } ?: throw IOException("Invalid LUN state: $id")
+    }
+}
+
+data class LunOwner(val uid: Int, val label: String?, val name: String) : ToSocket {
+    companion object : FromSocket<LunOwner> {
+        override fun fromSocket(stream: LocalSocket): LunOwner {
+            val uid = stream.inputStream.readIntLe()
+            val label = String(stream.inputStream.readData()).ifEmpty { null }
+            val name = String(stream.inputStream.readData())
+
+            return LunOwner(uid, label, name)
+        }
+    }
+
+    override fun toSocket(stream: LocalSocket) {
+        stream.outputStream.writeIntLe(uid)
+        stream.outputStream.writeData((label ?: "").toByteArray())
+        stream.outputStream.writeData(name.toByteArray())
+    }
+}
+
+data class ActiveMassStorageDevice(
+    val lun: Byte,
+    val file: String,
+    val cdrom: Boolean,
+    val ro: Boolean,
+    val removable: Boolean,
+    val nofua: Boolean,
+    val inquiryString: String,
+    val state: LunState,
+    val owner: LunOwner?,
+    val idleTimeoutMs: Int,
+    val idleMs: Long,
+    val lastReadMs: Long?,
+    val lastWriteMs: Long?,
+    val readBytes: Long?,
+    val writtenBytes: Long?,
+) : ToSocket {
     companion object : FromSocket<ActiveMassStorageDevice> {
         override fun fromSocket(stream: LocalSocket): ActiveMassStorageDevice {
+            val lun = stream.inputStream.readByte()
             val file = stream.inputStream.readData()
             val cdrom = stream.inputStream.readByte().toInt() != 0
             val ro = stream.inputStream.readByte().toInt() != 0
-
-            return ActiveMassStorageDevice(String(file), cdrom, ro)
+            val removable = stream.inputStream.readByte().toInt() != 0
+            val nofua = stream.inputStream.readByte().toInt() != 0
+            val inquiryString = String(stream.inputStream.readData())
+            val state = LunState.fromId(stream.inputStream.readByte())
+            val owner = if (stream.inputStream.readByte().toInt() != 0) {
+                LunOwner.fromSocket(stream)
+            } else {
+                null
+            }
+            val idleTimeoutMs = stream.inputStream.readIntLe()
+            val idleMs = stream.inputStream.readLongLe()
+            val lastReadMs = stream.inputStream.readOptionalLongLe()
+            val lastWriteMs = stream.inputStream.readOptionalLongLe()
+            val readBytes = stream.inputStream.readOptionalLongLe()
+            val writtenBytes = stream.inputStream.readOptionalLongLe()
+
+            return ActiveMassStorageDevice(
+                lun,
+                String(file),
+                cdrom,
+                ro,
+                removable,
+                nofua,
+                inquiryString,
+                state,
+                owner,
+                idleTimeoutMs,
+                idleMs,
+                lastReadMs,
+                lastWriteMs,
+                readBytes,
+                writtenBytes,
+            )
         }
     }
 
     override fun toSocket(stream: LocalSocket) {
+        stream.outputStream.writeByte(lun)
         stream.outputStream.writeData(file.toByteArray())
         stream.outputStream.writeByte(if (cdrom) { 1 } else { 0 })
         stream.outputStream.writeByte(if (ro) { 1 } else { 0 })
+        stream.outputStream.writeByte(if (removable) { 1 } else { 0 })
+        stream.outputStream.writeByte(if (nofua) { 1 } else { 0 })
+        stream.outputStream.writeData(inquiryString.toByteArray())
+        stream.outputStream.writeByte(state.id)
+        stream.outputStream.writeByte(if (owner != null) { 1 } else { 0 })
+        owner?.toSocket(stream)
+        stream.outputStream.writeIntLe(idleTimeoutMs)
+        stream.outputStream.writeLongLe(idleMs)
+        stream.outputStream.writeOptionalLongLe(lastReadMs)
+        stream.outputStream.writeOptionalLongLe(lastWriteMs)
+        stream.outputStream.writeOptionalLongLe(readBytes)
+        stream.outputStream.writeOptionalLongLe(writtenBytes)
     }
 }
 
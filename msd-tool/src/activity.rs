@@ -0,0 +1,155 @@
+// SPDX-FileCopyrightText: 2026 Andrew Gunnerson
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read},
+    mem::MaybeUninit,
+    os::fd::{AsRawFd, BorrowedFd, OwnedFd},
+};
+
+use rustix::{
+    fs::{
+        FileType,
+        inotify::{self, CreateFlags, ReadFlags, WatchFlags},
+    },
+    io::Errno,
+};
+
+use crate::util;
+
+fn file_ids(luns: &[(u8, BorrowedFd)]) -> io::Result<Vec<(u8, u64, u64)>> {
+    luns.iter()
+        .map(|(lun, fd)| util::file_id(*fd).map(|(dev, ino)| (*lun, dev, ino)))
+        .collect()
+}
+
+/// The types of I/O seen on a LUN's backing file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoActivity {
+    pub read: bool,
+    pub written: bool,
+}
+
+/// Cumulative I/O statistics of a block device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockStats {
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+}
+
+impl BlockStats {
+    /// Read the statistics of the block device that the fd refers to from
+    /// `/sys/dev/block/<major>:<minor>/stat`. Returns None if the fd does not
+    /// refer to a block device. Regular files have no equivalent.
+    pub fn read(fd: BorrowedFd) -> io::Result<Option<Self>> {
+        let stat = rustix::fs::fstat(fd)?;
+        if FileType::from_raw_mode(stat.st_mode) != FileType::BlockDevice {
+            return Ok(None);
+        }
+
+        let path = format!(
+            "/sys/dev/block/{}:{}/stat",
+            rustix::fs::major(stat.st_rdev),
+            rustix::fs::minor(stat.st_rdev),
+        );
+        let mut data = String::new();
+        File::open(&path)
+            .and_then(|f| util::check_fs_magic(f, util::SYSFS_MAGIC))?
+            .read_to_string(&mut data)?;
+
+        // The kernel always counts 512-byte sectors, regardless of the device's
+        // logical sector size.
+        let fields = data.split_whitespace().collect::<Vec<_>>();
+        let sectors = |index: usize| -> io::Result<u64> {
+            fields
+                .get(index)
+                .and_then(|f| f.parse::<u64>().ok())
+                .map(|s| s * 512)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid block device statistics: {path:?}: {data:?}"),
+                    )
+                })
+        };
+
+        Ok(Some(Self {
+            read_bytes: sectors(2)?,
+            written_bytes: sectors(6)?,
+        }))
+    }
+
+    /// Get the I/O that happened since an earlier snapshot.
+    pub fn since(self, base: Self) -> Self {
+        Self {
+            read_bytes: self.read_bytes.saturating_sub(base.read_bytes),
+            written_bytes: self.written_bytes.saturating_sub(base.written_bytes),
+        }
+    }
+}
+
+/// Detect host I/O to LUN backing files via inotify. The mass storage function
+/// accesses the files from a kernel thread, which still generates `IN_ACCESS`
+/// and `IN_MODIFY` events.
+pub struct IoMonitor {
+    fd: OwnedFd,
+    files: Vec<(u8, u64, u64)>,
+    /// LUNs backed by the same file share a watch.
+    watches: BTreeMap<i32, Vec<u8>>,
+}
+
+impl IoMonitor {
+    /// Watch the backing files of the specified LUNs.
+    pub fn new(luns: &[(u8, BorrowedFd)]) -> io::Result<Self> {
+        let fd = inotify::init(CreateFlags::CLOEXEC | CreateFlags::NONBLOCK)?;
+        let files = file_ids(luns)?;
+        let mut watches = BTreeMap::<i32, Vec<u8>>::new();
+
+        for (lun, file) in luns {
+            // The daemon only has the fd, not a path it can access.
+            let path = format!("/proc/self/fd/{}", file.as_raw_fd());
+            let wd = inotify::add_watch(&fd, path, WatchFlags::ACCESS | WatchFlags::MODIFY)?;
+
+            watches.entry(wd).or_default().push(*lun);
+        }
+
+        Ok(Self { fd, files, watches })
+    }
+
+    /// Check if this monitor is watching exactly the specified LUNs' backing
+    /// files.
+    pub fn is_watching(&self, luns: &[(u8, BorrowedFd)]) -> io::Result<bool> {
+        Ok(file_ids(luns)? == self.files)
+    }
+
+    /// Drain all pending events and return the LUNs that had I/O since the
+    /// previous call.
+    pub fn read_events(&self) -> io::Result<BTreeMap<u8, IoActivity>> {
+        let mut buf = [MaybeUninit::uninit(); 4096];
+        let mut reader = inotify::Reader::new(&self.fd, &mut buf);
+        let mut luns = BTreeMap::<u8, IoActivity>::new();
+
+        loop {
+            match reader.next() {
+                Ok(event) => {
+                    let Some(l) = self.watches.get(&event.wd()) else {
+                        continue;
+                    };
+
+                    for lun in l {
+                        let activity = luns.entry(*lun).or_default();
+                        activity.read |= event.events().contains(ReadFlags::ACCESS);
+                        activity.written |= event.events().contains(ReadFlags::MODIFY);
+                    }
+                }
+                Err(Errno::WOULDBLOCK) => break,
+                Err(Errno::INTR) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(luns)
+    }
+}
@@ -1,17 +1,25 @@
 // SPDX-FileCopyrightText: 2024 Andrew Gunnerson
 // SPDX-License-Identifier: GPL-3.0-only
 
-use std::{fs::File, os::unix::net::UnixStream, path::PathBuf};
+use std::{
+    fs::File,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
 
 use anyhow::{Context, Result, bail};
 use byteorder::{ReadBytesExt, WriteBytesExt};
-use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 
 use crate::{
     daemon,
     message::{
-        self, FromSocket, GetFunctionsRequest, GetMassStorageRequest, MassStorageDevice, Request,
-        Response, SetMassStorageRequest, ToSocket,
+        self, AddLunRequest, ChangeMediaRequest, DescriptorOverrides, FromSocket,
+        GetControllerStateRequest, GetFunctionsRequest, GetMassStorageRequest, Lease, LunState,
+        MassStorageDevice, MassStorageType, ReconnectRequest, RemoveLunRequest, RenewLeaseRequest,
+        Request, Response, SetClientNameRequest, SetMassStorageRequest, SpeedLimit, ToSocket,
     },
 };
 
@@ -35,12 +43,54 @@ fn negotiate_protocol(stream: &mut UnixStream) -> Result<()> {
     Ok(())
 }
 
+fn open_device(path: &Path, type_: MassStorageType, attrs: &LunArgs) -> Result<MassStorageDevice> {
+    let file = File::options()
+        .read(true)
+        .write(type_ == MassStorageType::DiskRw)
+        .open(path)
+        .with_context(|| format!("Failed to open file: {path:?}"))?;
+
+    Ok(MassStorageDevice {
+        fd: file.into(),
+        cdrom: type_ == MassStorageType::Cdrom,
+        ro: type_ != MassStorageType::DiskRw,
+        removable: !attrs.non_removable.unwrap_or_default(),
+        nofua: attrs.nofua.unwrap_or_default(),
+        inquiry_string: attrs.inquiry_string.clone().unwrap_or_default(),
+    })
+}
+
+/// Keep the connection to the daemon open until we're killed so that devices
+/// with a connection lease stay active.
+fn hold_connection() -> ! {
+    eprintln!("Mass storage devices will be cleared when this process exits");
+
+    loop {
+        thread::park();
+    }
+}
+
 pub fn subcommand_client(cli: &ClientCli) -> Result<()> {
     let mut stream = UnixStream::connect_addr(&daemon::socket_addr())
         .context("Failed to connect to domain socket")?;
 
     negotiate_protocol(&mut stream)?;
 
+    if let Some(name) = &cli.name {
+        let request = Request::SetClientName(SetClientNameRequest { name: name.clone() });
+        request
+            .to_socket(&mut stream)
+            .with_context(|| format!("Failed to send request: {request:?}"))?;
+
+        let response = Response::from_socket(&mut stream).context("Failed to receive response")?;
+
+        match response {
+            Response::Error(r) => bail!("{}", r.message),
+            Response::SetClientName(_) => {}
+            r => bail!("Invalid response: {r:?}"),
+        }
+    }
+
     match &cli.command {
         ClientCommand::GetFunctions(_) => {
             let request = Request::GetFunctions(GetFunctionsRequest);
@@ -97,17 +147,19 @@ pub fn subcommand_client(cli: &ClientCli) -> Result<()> {
             let mut devices = vec![];
 
             for (type_, path) in c.type_.iter().zip(c.file.iter()) {
-                let file =
-                    File::open(path).with_context(|| format!("Failed to open file: {path:?}"))?;
-
-                devices.push(MassStorageDevice {
-                    fd: file.into(),
-                    cdrom: *type_ == MassStorageType::Cdrom,
-                    ro: *type_ != MassStorageType::DiskRw,
-                });
+                devices.push(open_device(path, *type_, &c.attrs)?);
             }
 
-            let request = Request::SetMassStorage(SetMassStorageRequest { devices });
+            let request = Request::SetMassStorage(SetMassStorageRequest {
+                devices,
+                lease: c.lease.lease(),
+                idle_timeout_ms: c.lease.idle_timeout_ms.unwrap_or_default(),
+                one_shot: c.lease.one_shot,
+                force: c.force,
+                stall: !c.no_stall,
+                descriptors: c.descriptors.overrides(),
+                max_speed: c.max_speed.map(SpeedLimit::from),
+            });
             request
                 .to_socket(&mut stream)
                 .with_context(|| format!("Failed to send request: {request:?}"))?;
@@ -120,6 +172,107 @@ pub fn subcommand_client(cli: &ClientCli) -> Result<()> {
                 Response::SetMassStorage(_) => {}
                 r => bail!("Invalid response: {r:?}"),
             }
+
+            if c.lease.session {
+                hold_connection();
+            }
+        }
+        ClientCommand::AddLun(c) => {
+            let request = Request::AddLun(AddLunRequest {
+                device: open_device(&c.file, c.type_, &c.attrs)?,
+                lease: c.lease.lease(),
+                idle_timeout_ms: c.lease.idle_timeout_ms.unwrap_or_default(),
+                one_shot: c.lease.one_shot,
+            });
+            request
+                .to_socket(&mut stream)
+                .with_context(|| format!("Failed to send request: {request:?}"))?;
+
+            let response =
+                Response::from_socket(&mut stream).context("Failed to receive response")?;
+
+            match response {
+                Response::Error(r) => bail!("{}", r.message),
+                Response::AddLun(r) => println!("LUN #{}", r.lun),
+                r => bail!("Invalid response: {r:?}"),
+            }
+
+            if c.lease.session {
+                hold_connection();
+            }
+        }
+        ClientCommand::ChangeMedia(c) => {
+            // The LUN's current type and attributes are kept unless they are
+            // overridden.
+            let request = Request::GetMassStorage(GetMassStorageRequest);
+            request
+                .to_socket(&mut stream)
+                .with_context(|| format!("Failed to send request: {request:?}"))?;
+
+            let response =
+                Response::from_socket(&mut stream).context("Failed to receive response")?;
+
+            let devices = match response {
+                Response::Error(r) => bail!("{}", r.message),
+                Response::GetMassStorage(r) => r.devices,
+                r => bail!("Invalid response: {r:?}"),
+            };
+            let current = devices.iter().find(|d| d.lun == c.lun);
+
+            let type_ = match (c.type_, current) {
+                (Some(t), _) => t,
+                (None, Some(d)) => MassStorageType::new(d.cdrom, d.ro),
+                (None, None) => bail!("LUN #{} has no media; specify the type with -t", c.lun),
+            };
+
+            let mut device = open_device(&c.file, type_, &c.attrs)?;
+            if let Some(current) = current {
+                if c.attrs.non_removable.is_none() {
+                    device.removable = current.removable;
+                }
+                if c.attrs.nofua.is_none() {
+                    device.nofua = current.nofua;
+                }
+                if c.attrs.inquiry_string.is_none() {
+                    device.inquiry_string = current.inquiry_string.clone();
+                }
+            }
+
+            let request = Request::ChangeMedia(ChangeMediaRequest {
+                lun: c.lun,
+                device,
+                force: c.force,
+            });
+            request
+                .to_socket(&mut stream)
+                .with_context(|| format!("Failed to send request: {request:?}"))?;
+
+            let response =
+                Response::from_socket(&mut stream).context("Failed to receive response")?;
+
+            match response {
+                Response::Error(r) => bail!("{}", r.message),
+                Response::ChangeMedia(_) => {}
+                r => bail!("Invalid response: {r:?}"),
+            }
+        }
+        ClientCommand::RemoveLun(c) => {
+            let request = Request::RemoveLun(RemoveLunRequest {
+                lun: c.lun,
+                force: c.force,
+            });
+            request
+                .to_socket(&mut stream)
+                .with_context(|| format!("Failed to send request: {request:?}"))?;
+
+            let response =
+                Response::from_socket(&mut stream).context("Failed to receive response")?;
+
+            match response {
+                Response::Error(r) => bail!("{}", r.message),
+                Response::RemoveLun(_) => {}
+                r => bail!("Invalid response: {r:?}"),
+            }
         }
         ClientCommand::GetMassStorage(_) => {
             let request = Request::GetMassStorage(GetMassStorageRequest);
@@ -134,19 +287,158 @@ pub fn subcommand_client(cli: &ClientCli) -> Result<()> {
                 Response::Error(r) => bail!("{}", r.message),
                 Response::GetMassStorage(r) => {
                     for device in r.devices {
-                        let type_ = match (device.cdrom, device.ro) {
-                            (true, _) => MassStorageType::Cdrom,
-                            (false, true) => MassStorageType::DiskRo,
-                            (false, false) => MassStorageType::DiskRw,
-                        };
+                        let type_ = MassStorageType::new(device.cdrom, device.ro);
                         let type_value = type_.to_possible_value().unwrap();
 
-                        println!("{} -> {:?}", type_value.get_name(), device.file);
+                        match device.state {
+                            LunState::Active => {
+                                println!(
+                                    "#{}: {} -> {:?}",
+                                    device.lun,
+                                    type_value.get_name(),
+                                    device.file,
+                                );
+                            }
+                            LunState::EjectedByHost => {
+                                println!(
+                                    "#{}: {} -> {:?} (ejected by host)",
+                                    device.lun,
+                                    type_value.get_name(),
+                                    device.file,
+                                );
+                            }
+                            LunState::EjectedOnChange => {
+                                println!(
+                                    "#{}: {} -> {:?} (ejected due to external modification)",
+                                    device.lun,
+                                    type_value.get_name(),
+                                    device.file,
+                                );
+                            }
+                        }
+
+                        println!(
+                            "  Attributes: removable={}, nofua={}, inquiry_string={:?}",
+                            device.removable, device.nofua, device.inquiry_string,
+                        );
+
+                        if device.idle_timeout_ms != 0 {
+                            println!(
+                                "  Idle: {:?} of {:?}",
+                                Duration::from_millis(device.idle_ms),
+                                Duration::from_millis(device.idle_timeout_ms.into()),
+                            );
+                        }
+
+                        let ago = |ms: Option<u64>| match ms {
+                            Some(ms) => format!("{:?} ago", Duration::from_millis(ms)),
+                            None => "never".to_owned(),
+                        };
+                        println!(
+                            "  Host I/O: last read {}, last write {}",
+                            ago(device.last_read_ms),
+                            ago(device.last_write_ms),
+                        );
+
+                        if let (Some(read), Some(written)) =
+                            (device.read_bytes, device.written_bytes)
+                        {
+                            println!("  Device I/O bytes: read {read}, written {written}");
+                        }
+
+                        if let Some(owner) = device.owner {
+                            println!(
+                                "  Owner: uid={}, context={}, name={:?}",
+                                owner.uid,
+                                owner.label.as_deref().unwrap_or("<unknown>"),
+                                owner.name,
+                            );
+                        }
+                    }
+                }
+                r => bail!("Invalid response: {r:?}"),
+            }
+        }
+        ClientCommand::GetControllerState(_) => {
+            let request = Request::GetControllerState(GetControllerStateRequest);
+            request
+                .to_socket(&mut stream)
+                .with_context(|| format!("Failed to send request: {request:?}"))?;
+
+            let response =
+                Response::from_socket(&mut stream).context("Failed to receive response")?;
+
+            match response {
+                Response::Error(r) => bail!("{}", r.message),
+                Response::GetControllerState(r) => {
+                    println!("Controller: {}", r.controller);
+                    println!(
+                        "Bound controller: {}",
+                        r.bound_controller.as_deref().unwrap_or("<none>"),
+                    );
+                    println!("State: {}", r.state);
+                    println!("Current speed: {}", r.current_speed);
+                    println!("Maximum speed: {}", r.maximum_speed);
+                    println!(
+                        "Gadget maximum speed: {}",
+                        r.gadget_max_speed.as_deref().unwrap_or("<unsupported>"),
+                    );
+                    println!(
+                        "Wake lock: {}",
+                        match r.wake_lock_held {
+                            None => "<disabled>",
+                            Some(false) => "released",
+                            Some(true) => "held",
+                        },
+                    );
+                    println!("OTG: {}", r.is_otg);
+
+                    if let Some(state) = r.host_state {
+                        println!(
+                            "Host state: {state:?} (for {:?})",
+                            Duration::from_millis(r.host_state_duration_ms),
+                        );
+                        println!("Host connect count: {}", r.host_connect_count);
+                        println!("Host configure count: {}", r.host_configure_count);
+                    } else {
+                        println!("Host state: <unknown>");
                     }
                 }
                 r => bail!("Invalid response: {r:?}"),
             }
         }
+        ClientCommand::Reconnect(c) => {
+            let request = Request::Reconnect(ReconnectRequest {
+                delay_ms: c.delay_ms,
+            });
+            request
+                .to_socket(&mut stream)
+                .with_context(|| format!("Failed to send request: {request:?}"))?;
+
+            let response =
+                Response::from_socket(&mut stream).context("Failed to receive response")?;
+
+            match response {
+                Response::Error(r) => bail!("{}", r.message),
+                Response::Reconnect(_) => {}
+                r => bail!("Invalid response: {r:?}"),
+            }
+        }
+        ClientCommand::RenewLease(_) => {
+            let request = Request::RenewLease(RenewLeaseRequest);
+            request
+                .to_socket(&mut stream)
+                .with_context(|| format!("Failed to send request: {request:?}"))?;
+
+            let response =
+                Response::from_socket(&mut stream).context("Failed to receive response")?;
+
+            match response {
+                Response::Error(r) => bail!("{}", r.message),
+                Response::RenewLease(_) => {}
+                r => bail!("Invalid response: {r:?}"),
+            }
+        }
     }
 
     Ok(())
@@ -156,11 +448,138 @@ pub fn subcommand_client(cli: &ClientCli) -> Result<()> {
 #[derive(Debug, Parser)]
 struct GetFunctionsCli;
 
+#[derive(Debug, Args)]
+struct LeaseArgs {
+    /// Clear the devices when this command exits.
+    ///
+    /// The command keeps running until it is killed.
+    #[clap(long, conflicts_with = "lease_timeout_ms")]
+    session: bool,
+
+    /// Clear the devices unless the lease is renewed within this many
+    /// milliseconds.
+    ///
+    /// The lease is renewed with the renew-lease command. Expiry is checked
+    /// every couple of seconds, so the devices may remain a bit longer.
+    #[clap(long, value_name = "MS")]
+    lease_timeout_ms: Option<u32>,
+
+    /// Clear the devices after the host has not accessed them for this many
+    /// milliseconds.
+    ///
+    /// The daemon's default timeout is used if unspecified.
+    #[clap(long, value_name = "MS", value_parser = clap::value_parser!(u32).range(1..))]
+    idle_timeout_ms: Option<u32>,
+
+    /// Clear the devices after the host resets the device this many times or
+    /// ejects the media.
+    ///
+    /// This is useful for booting an installer only once. A reset only counts
+    /// if the host does not use the device again for 10 seconds, which usually
+    /// happens when it reboots. Brief resets while the host boots are ignored.
+    /// If 0, the devices are only cleared when the host ejects the media.
+    #[clap(long, value_name = "RESETS", num_args = 0..=1, default_missing_value = "1")]
+    one_shot: Option<u32>,
+}
+
+impl LeaseArgs {
+    fn lease(&self) -> Lease {
+        if self.session {
+            Lease::Connection
+        } else if let Some(timeout_ms) = self.lease_timeout_ms {
+            Lease::Timeout { timeout_ms }
+        } else {
+            Lease::None
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct LunArgs {
+    /// Prevent the host from ejecting the media.
+    ///
+    /// Some BIOSes only boot from non-removable disks. With change-media,
+    /// `false` can be passed to clear the LUN's existing value.
+    #[clap(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    non_removable: Option<bool>,
+
+    /// Ignore the host's force unit access (FUA) flag.
+    ///
+    /// With change-media, `false` can be passed to clear the LUN's existing
+    /// value.
+    #[clap(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    nofua: Option<bool>,
+
+    /// Vendor (8 chars), product (16 chars), and revision (4 chars) reported
+    /// to the host.
+    ///
+    /// The kernel's default is used if unspecified.
+    #[clap(long, value_name = "STRING")]
+    inquiry_string: Option<String>,
+}
+
+fn parse_hex_u16(value: &str) -> Result<u16, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+
+    u16::from_str_radix(digits, 16).map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Args)]
+struct DescriptorArgs {
+    /// USB vendor ID (hex) reported while mass storage is active.
+    #[clap(long, value_name = "HEX", value_parser = parse_hex_u16)]
+    id_vendor: Option<u16>,
+
+    /// USB product ID (hex) reported while mass storage is active.
+    #[clap(long, value_name = "HEX", value_parser = parse_hex_u16)]
+    id_product: Option<u16>,
+
+    /// USB device release number (hex) reported while mass storage is active.
+    #[clap(long, value_name = "HEX", value_parser = parse_hex_u16)]
+    bcd_device: Option<u16>,
+
+    /// Manufacturer string reported while mass storage is active.
+    #[clap(long, value_name = "STRING")]
+    manufacturer: Option<String>,
+
+    /// Product string reported while mass storage is active.
+    #[clap(long, value_name = "STRING")]
+    product: Option<String>,
+
+    /// Serial number string reported while mass storage is active.
+    #[clap(long, value_name = "STRING")]
+    serial_number: Option<String>,
+}
+
+impl DescriptorArgs {
+    fn overrides(&self) -> DescriptorOverrides {
+        DescriptorOverrides {
+            id_vendor: self.id_vendor,
+            id_product: self.id_product,
+            bcd_device: self.bcd_device,
+            manufacturer: self.manufacturer.clone(),
+            product: self.product.clone(),
+            serial_number: self.serial_number.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
-enum MassStorageType {
-    Cdrom,
-    DiskRo,
-    DiskRw,
+enum MaxSpeed {
+    HighSpeed,
+    FullSpeed,
+}
+
+impl From<MaxSpeed> for SpeedLimit {
+    fn from(value: MaxSpeed) -> Self {
+        match value {
+            MaxSpeed::HighSpeed => Self::HighSpeed,
+            MaxSpeed::FullSpeed => Self::FullSpeed,
+        }
+    }
 }
 
 /// Set USB controller to emulate mass storage devices.
@@ -170,32 +589,165 @@ enum MassStorageType {
 ///
 /// This command always replaces all mass storage devices. To remove all of
 /// them, don't specify any files.
+///
+/// The LUN attribute options apply to every device. To use different
+/// attributes for each device, use add-lun.
+///
+/// The USB descriptor options override the gadget's identity while mass
+/// storage is active. The original descriptors are restored when the devices
+/// are cleared.
 #[derive(Debug, Parser)]
 struct SetMassStorageCli {
-    /// Disk image or ISO file.
+    /// Disk image, ISO file, or block device.
     #[clap(short, long, value_parser)]
     file: Vec<PathBuf>,
 
     /// Mass storage device type.
     #[clap(short, long)]
     type_: Vec<MassStorageType>,
+
+    #[command(flatten)]
+    attrs: LunArgs,
+
+    /// Do not allow the function to halt bulk endpoints.
+    ///
+    /// This works around hosts that do not handle stalls properly.
+    #[clap(long)]
+    no_stall: bool,
+
+    #[command(flatten)]
+    descriptors: DescriptorArgs,
+
+    /// Limit the USB speed while mass storage is active.
+    ///
+    /// Some old BIOSes fail to boot from SuperSpeed devices. The negotiated
+    /// speed is shown by get-controller-state.
+    #[clap(long)]
+    max_speed: Option<MaxSpeed>,
+
+    #[command(flatten)]
+    lease: LeaseArgs,
+
+    /// Replace devices even if they are owned by another client.
+    #[clap(long)]
+    force: bool,
 }
 
 /// Get currently active mass storage devices.
 #[derive(Debug, Parser)]
 struct GetMassStorageCli;
 
+/// Get USB controller state, including whether a host is connected.
+///
+/// A state of "configured" means that the host has enumerated and configured
+/// the device.
+#[derive(Debug, Parser)]
+struct GetControllerStateCli;
+
+/// Simulate unplugging and replugging the USB cable.
+///
+/// This forces the host to enumerate the device again without changing the
+/// active USB gadget functions.
+#[derive(Debug, Parser)]
+struct ReconnectCli {
+    /// How long to keep the device disconnected in milliseconds.
+    #[clap(short, long, value_name = "MS", default_value_t = 1000)]
+    delay_ms: u32,
+}
+
+/// Add a mass storage device without affecting the existing devices.
+///
+/// The host will briefly see the USB device disconnect because the kernel does
+/// not allow adding devices while the USB controller is in use.
+#[derive(Debug, Parser)]
+struct AddLunCli {
+    /// Disk image, ISO file, or block device.
+    #[clap(short, long, value_parser)]
+    file: PathBuf,
+
+    /// Mass storage device type.
+    #[clap(short, long)]
+    type_: MassStorageType,
+
+    #[command(flatten)]
+    attrs: LunArgs,
+
+    #[command(flatten)]
+    lease: LeaseArgs,
+}
+
+/// Remove a mass storage device without affecting the other devices.
+///
+/// The host will briefly see the USB device disconnect because the kernel does
+/// not allow removing devices while the USB controller is in use.
+#[derive(Debug, Parser)]
+struct RemoveLunCli {
+    /// LUN number as shown by get-mass-storage.
+    #[clap(short, long)]
+    lun: u8,
+
+    /// Remove the device even if it is owned by another client.
+    #[clap(long)]
+    force: bool,
+}
+
+/// Swap the media of an existing mass storage device.
+///
+/// Like with a real optical drive, the host only sees the media being ejected
+/// and inserted. The USB device stays connected. This is only possible for
+/// removable devices.
+///
+/// The device's current type and LUN attributes are kept unless overridden.
+#[derive(Debug, Parser)]
+struct ChangeMediaCli {
+    /// LUN number as shown by get-mass-storage.
+    #[clap(short, long)]
+    lun: u8,
+
+    /// New disk image or ISO file.
+    #[clap(short, long, value_parser)]
+    file: PathBuf,
+
+    /// Mass storage device type. Defaults to the device's current type.
+    #[clap(short, long)]
+    type_: Option<MassStorageType>,
+
+    #[command(flatten)]
+    attrs: LunArgs,
+
+    /// Change the media even if the device is owned by another client.
+    #[clap(long)]
+    force: bool,
+}
+
+/// Renew the lease of mass storage devices set with --lease-timeout-ms.
+///
+/// Only the devices owned by this client are renewed, so the same --name must
+/// be used as when the devices were set.
+#[derive(Debug, Parser)]
+struct RenewLeaseCli;
+
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Subcommand)]
 enum ClientCommand {
     GetFunctions(GetFunctionsCli),
     SetMassStorage(SetMassStorageCli),
     GetMassStorage(GetMassStorageCli),
+    GetControllerState(GetControllerStateCli),
+    Reconnect(ReconnectCli),
+    RenewLease(RenewLeaseCli),
+    AddLun(AddLunCli),
+    RemoveLun(RemoveLunCli),
+    ChangeMedia(ChangeMediaCli),
 }
 
 /// Send messages to daemon.
 #[derive(Debug, Parser)]
 pub struct ClientCli {
+    /// Name to identify this client as the owner of mass storage devices.
+    #[clap(long, global = true)]
+    name: Option<String>,
+
     #[command(subcommand)]
     command: ClientCommand,
 }
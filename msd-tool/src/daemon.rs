@@ -2,9 +2,12 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 //! This module implements the daemon that runs as the system user and listens
-//! for requests from the app. The only actions possible are querying the
-//! currently active functions and setting the USB controller to emulate mass
-//! storage devices.
+//! for requests from the app. Clients can query the USB functions, controller,
+//! and host state, and configure mass storage LUNs, either all at once or by
+//! adding, removing, or changing the media of individual LUNs. LUNs can be
+//! leased to a client session and are removed once the lease expires or the
+//! session ends. The USB connection can be reset and the configuration can
+//! optionally be persisted across daemon restarts.
 //!
 //! Access control is handled entirely by the SELinux policy. If SELinux is not
 //! enforcing at the time of the connection, the connection will be terminated.
@@ -13,17 +16,27 @@
 //! requests result in an [`ErrorResponse`].
 
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
+    env,
     ffi::{OsStr, OsString},
     fs::{self, File},
-    io,
+    io::{self, BufRead, Write},
     os::{
-        fd::{AsFd, AsRawFd},
-        unix::net::{SocketAddr, UnixListener, UnixStream},
+        fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+        unix::{
+            fs::OpenOptionsExt,
+            net::{SocketAddr, UnixListener, UnixStream},
+            process::CommandExt,
+        },
+    },
+    path::{Component, Path, PathBuf},
+    process::{self, Child, ChildStdin, Stdio},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
     },
-    path::Path,
-    sync::Mutex,
     thread,
+    time::{Duration, Instant},
 };
 
 #[cfg(target_os = "android")]
@@ -33,24 +46,48 @@ use std::os::linux::net::SocketAddrExt;
 
 use anyhow::{Context, Result, anyhow, bail};
 use byteorder::{ReadBytesExt, WriteBytesExt};
-use clap::Parser;
+use cap_std::{
+    ambient_authority,
+    fs::{Dir, OpenOptions},
+};
+use clap::{Parser, ValueEnum};
 use rustix::{
-    fs::{FileType, Gid, Mode, Uid},
+    event::{PollFd, PollFlags},
+    fs::{FileType, Gid, Mode, OFlags, Uid},
+    io::Errno,
+    net::UCred,
+    process::{Pid, Signal},
     thread::{CapabilitySet, CapabilitySets},
 };
 use tracing::{debug, error, info, info_span, warn};
 
 use crate::{
+    activity::{BlockStats, IoActivity, IoMonitor},
+    host::{HostEvent, HostStatus},
+    loopdev::LoopDevice,
     message::{
-        self, ActiveMassStorageDevice, ErrorResponse, FromSocket, GetFunctionsResponse,
-        GetMassStorageResponse, Request, Response, SetMassStorageRequest, SetMassStorageResponse,
-        ToSocket,
+        self, ActiveMassStorageDevice, AddLunRequest, AddLunResponse, ChangeMediaRequest,
+        ChangeMediaResponse, DescriptorOverrides, ErrorResponse, FromSocket,
+        GetControllerStateResponse, GetFunctionsResponse, GetMassStorageResponse, HostState, Lease,
+        LunOwner, LunState, MassStorageDevice, MassStorageType, ReconnectRequest,
+        ReconnectResponse, RemoveLunRequest, RemoveLunResponse, RenewLeaseResponse, Request,
+        Response, SetClientNameRequest, SetClientNameResponse, SetMassStorageRequest,
+        SetMassStorageResponse, SpeedLimit, ToSocket,
+    },
+    persist::{self, ConfigFile, PersistedConfig, PersistedGadget, PersistedLun},
+    power::WakeLock,
+    uevent::UeventSocket,
+    usb::{
+        GadgetDescriptors, LunAttrs, MassStorageFunction, UsbController, UsbGadget, UsbStateWatcher,
+    },
+    util::{
+        self, BootInstant, Cgroup, CgroupFreezer, FileLock, Process, ProcessIter, ProcessStopper,
     },
-    usb::UsbGadget,
-    util::{self, ProcessIter, ProcessStopper},
 };
 
 const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+/// Lower filesystem of Android's FUSE filesystem for emulated storage.
+const MEDIA_ROOT: &str = "/data/media";
 
 // AOSP hardcodes these.
 const GADGET_ROOT: &str = "/config/usb_gadget/g1";
@@ -60,8 +97,44 @@ const FUNCTION_PREFIX: &str = "mass_storage.";
 const FUNCTION_NAME_DEFAULT: &str = "mass_storage.msd";
 const CONFIG_NAME: &str = "msd";
 
+const WAKE_LOCK_NAME: &str = "msd";
+/// Client name for LUNs that were restored from the saved configuration.
+const RESTORE_OWNER_NAME: &str = "restore";
+/// Client name for LUNs that were attached automatically on connection.
+const DEFAULT_OWNER_NAME: &str = "default";
+
+/// SELinux domains of the gadget HAL that the policy from `sepatch` grants the
+/// daemon access to. Pixel devices use `hal_usb_gadget_impl` while AOSP's
+/// reference implementation uses `hal_usb_gadget_default`.
+pub const GADGET_HAL_DOMAINS: &[&str] = &["hal_usb_gadget_impl", "hal_usb_gadget_default"];
 const GADGET_HAL_PROCESS: &str = "android.hardware.usb.gadget-service";
 
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the watchdog tries to take over the socket from its dead daemon.
+const WATCHDOG_BIND_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a new daemon waits for the watchdog of a previous daemon.
+const DAEMON_BIND_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const LUN_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long host resets are ignored after the daemon rebinds the gadget.
+const RESET_SETTLE_TIME: Duration = Duration::from_secs(5);
+/// How long the host must stay unconfigured for a host reset to count. Hosts
+/// reset the device several times while booting, like when the OS takes over
+/// from the firmware, but a reboot usually takes longer.
+const RESET_DEBOUNCE_TIME: Duration = Duration::from_secs(10);
+
+/// 8 byte vendor, 16 byte product, and 4 byte revision.
+const INQUIRY_STRING_MAX_LEN: usize = 28;
+/// Maximum length of a USB gadget string descriptor in configfs.
+const USB_MAX_STRING_LEN: usize = 126;
+/// FSG_MAX_LUNS in the kernel.
+const MAX_LUNS: usize = 16;
+/// The kernel uses 2048 byte blocks for CD-ROMs and 512 byte blocks for disks.
+const CDROM_BLOCK_SIZE: u64 = 2048;
+const DISK_BLOCK_SIZE: u64 = 512;
+/// Largest logical sector size of block devices that hosts generally support.
+const MAX_SECTOR_SIZE: u64 = 4096;
+
 pub fn socket_addr() -> SocketAddr {
     SocketAddr::from_abstract_name("msdd").expect("Invalid abstract socket name")
 }
@@ -160,66 +233,36 @@ fn handle_get_functions_request() -> Result<BTreeMap<OsString, OsString>> {
     gadget.configs()
 }
 
-fn handle_set_mass_storage_request(request: &SetMassStorageRequest) -> Result<()> {
-    static LOCK: Mutex<()> = Mutex::new(());
-    let _lock = LOCK.lock().unwrap();
-
-    for device in &request.devices {
-        debug!("Checking device request: {device:?}");
-
-        let fd_path = format!("/proc/self/fd/{}", device.fd.as_raw_fd());
-
-        match fs::read_link(&fd_path) {
-            Ok(p) => debug!("- Path: {p:?}"),
-            Err(e) => warn!("- Path: <Unknown>: {e:?}"),
-        }
-
-        match util::fd_get_label(device.fd.as_fd()) {
-            Ok(l) => debug!("- Label: {l:?}"),
-            Err(e) => warn!("- Label: <Unknown>: {e:?}"),
-        }
-
-        let stat = rustix::fs::fstat(&device.fd)
-            .with_context(|| format!("Failed to stat file: {:?}", device.fd))?;
-        let file_type = FileType::from_raw_mode(stat.st_mode);
-
-        debug! {"- Type: {file_type:?}"};
-        debug! {"- Mode: {:o}", Mode::from_raw_mode(stat.st_mode)};
-        debug! {"- UID: {}", stat.st_uid};
-        debug! {"- GID: {}", stat.st_gid};
-        debug! {"- Size: {}", stat.st_size};
-
-        if file_type != FileType::RegularFile {
-            bail!("Not a regular file: {:?}: {file_type:?}", device.fd);
-        }
+/// Check if a process is a gadget HAL. Processes are primarily identified by
+/// their SELinux domain because vendor HALs may have arbitrary executable
+/// names. The executable name is used as a fallback in case the process' label
+/// cannot be read.
+fn is_gadget_hal(process: &Process, domains: &[String]) -> bool {
+    if let Some(domain) = process.domain()
+        && domains.iter().any(|d| d == domain)
+    {
+        return true;
     }
 
-    let config_name = OsStr::new(CONFIG_NAME);
-    let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
-    let function_name = detect_function_name(&gadget)?;
+    // The Pixel 6 Pro has a ".gs101" suffix.
+    if let Some(name) = process.name.as_deref().and_then(|n| n.to_str()) {
+        name.starts_with(GADGET_HAL_PROCESS)
+    } else {
+        false
+    }
+}
 
-    // We need to SIGSTOP this process while we make our changes to prevent it
-    // from constantly trying to ensure that UDC is set to the expected value.
-    // Stopping the `vendor.usb-gadget-hal` init service would be cleaner, but
-    // does not work because the HAL fails restore its state properly after it
-    // starts back up, causing UDC to be cleared every time the device is
-    // unplugged.
-    let gadget_hal_stoppers = ProcessIter::new()
+/// Find all running gadget HAL processes.
+fn find_gadget_hal(domains: &[String]) -> Result<Vec<Process>> {
+    ProcessIter::new()
         .context("Failed to search running processes")?
         .filter(|result| {
-            if let Ok((_, name)) = result {
-                // The Pixel 6 Pro has a ".gs101" suffix. If the naming becomes
-                // too convoluted in the future, we can filter by SELinux label.
-                if let Some(name) = name.to_str() {
-                    name.starts_with(GADGET_HAL_PROCESS)
-                } else {
-                    false
-                }
+            if let Ok(process) = result {
+                is_gadget_hal(process, domains)
             } else {
                 true
             }
         })
-        .map(|r| r.and_then(|(fd, _)| ProcessStopper::new(fd).map_err(io::Error::from)))
         // Ignore ENOSYS when pidfd is unsupported. This will never happen on
         // supported Android versions, but the daemon needs to be able to run on
         // the Android 10 emulator to test sdcardfs.
@@ -228,238 +271,3067 @@ fn handle_set_mass_storage_request(request: &SetMassStorageRequest) -> Result<()
                 .is_err_and(|e| e.kind() == io::ErrorKind::Unsupported)
         })
         .collect::<io::Result<Vec<_>>>()
-        .context("Failed to search for gadget HAL process")?;
-    if gadget_hal_stoppers.is_empty() {
-        warn!("No gadget HAL process found: {GADGET_HAL_PROCESS}*");
+        .context("Failed to search for gadget HAL process")
+}
+
+/// Group gadget HAL processes by the cgroup v2 group that they belong to.
+/// Processes that are not part of a cgroup v2 hierarchy are returned
+/// separately.
+fn group_gadget_hal<'a>(
+    processes: impl IntoIterator<Item = &'a Process>,
+) -> (BTreeMap<PathBuf, Vec<&'a Process>>, Vec<&'a Process>) {
+    let mut groups = BTreeMap::<PathBuf, Vec<&Process>>::new();
+    let mut remaining = vec![];
+
+    for process in processes {
+        match util::process_cgroup(process.pid) {
+            Ok(Some(path)) => groups.entry(path).or_default().push(process),
+            Ok(None) => {
+                debug!("Process {:?} is not in a cgroup v2 hierarchy", process.pid);
+                remaining.push(process);
+            }
+            Err(e) => {
+                warn!("Failed to get cgroup of process {:?}: {e}", process.pid);
+                remaining.push(process);
+            }
+        }
     }
 
-    let Some(controller) = usb_controller()? else {
-        bail!("Cannot determine ID of USB controller");
-    };
+    (groups, remaining)
+}
 
-    debug!("Disassociating gadget config from controller");
-    gadget.set_controller(None)?;
+/// Open a gadget HAL cgroup. This fails if the cgroup contains any processes
+/// besides the specified gadget HAL processes or if it has child cgroups,
+/// which would be frozen too.
+fn open_gadget_hal_cgroup(path: &Path, group: &[&Process]) -> io::Result<Cgroup> {
+    let cgroup = Cgroup::open(path)?;
+
+    let descendants = cgroup.nr_descendants()?;
+    if descendants != 0 {
+        return Err(io::Error::other(format!(
+            "cgroup has {descendants} descendant cgroups",
+        )));
+    }
 
-    if gadget.delete_config(config_name)? {
-        debug!("Deleted old mass storage config");
+    let procs = cgroup.procs()?;
+    if let Some(pid) = procs.iter().find(|p| !group.iter().any(|g| g.pid == **p)) {
+        return Err(io::Error::other(format!(
+            "cgroup contains unrelated process: {pid:?}",
+        )));
     }
 
-    // Extra LUNs must be deleted first, but lun.0 cannot be deleted.
-    if let Some(function) = gadget.open_mass_storage_function(&function_name)? {
-        for lun in function.luns()? {
-            if lun == 0 {
-                function.clear_lun(lun)?;
-                debug!("Unregistered LUN #{lun}");
-            } else if function.delete_lun(lun)? {
-                debug!("Deleted LUN #{lun}");
+    Ok(cgroup)
+}
+
+/// Find the cgroups containing the gadget HAL processes that can be frozen. A
+/// cgroup can only be frozen if it contains nothing but gadget HAL processes.
+/// Returns the cgroups along with the PIDs of the processes in them.
+fn gadget_hal_cgroups(processes: &[Process]) -> Vec<(PathBuf, Cgroup, Vec<Pid>)> {
+    let (groups, _) = group_gadget_hal(processes);
+    let mut result = vec![];
+
+    for (path, group) in groups {
+        match open_gadget_hal_cgroup(&path, &group) {
+            Ok(cgroup) => {
+                let pids = group.iter().map(|p| p.pid).collect();
+                result.push((path, cgroup, pids));
             }
+            Err(e) => warn!("Cannot freeze cgroup {path:?}; falling back to SIGSTOP: {e}"),
         }
     }
 
-    // On Samsung devices, mass storage gadget functions cannot be recreated.
-    if function_name == FUNCTION_NAME_DEFAULT && gadget.delete_function(&function_name)? {
-        debug!("Deleted old mass storage function");
-    }
-
-    if !request.devices.is_empty() {
-        if gadget.create_function(&function_name)? {
-            debug!("Created mass storage function");
-        }
+    result
+}
 
-        let function = gadget
-            .open_mass_storage_function(&function_name)?
-            .ok_or_else(|| anyhow!("Newly created function does not exist: {function_name:?}"))?;
-        for (lun, device) in request.devices.iter().enumerate() {
-            // lun.0 exists by default.
-            if lun > 0 && function.create_lun(lun as u8)? {
-                debug!("Created LUN #{lun}");
+/// Resume gadget HAL processes that may have been left paused by a daemon
+/// process that exited without running destructors, for example due to being
+/// killed or aborting on panic. This thaws frozen gadget HAL cgroups and sends
+/// SIGCONT to every gadget HAL process. Neither operation has an effect on a
+/// process that is already running.
+fn resume_gadget_hal(domains: &[String]) -> Result<()> {
+    let processes = find_gadget_hal(domains)?;
+    let (groups, _) = group_gadget_hal(&processes);
+
+    for (path, group) in groups {
+        let result = open_gadget_hal_cgroup(&path, &group).and_then(|cgroup| {
+            if cgroup.is_frozen()? {
+                info!("Thawing gadget HAL cgroup: {path:?}");
+                cgroup.set_frozen(false)?;
             }
 
-            debug!("Associating LUN #{lun} with {device:?}");
-            function.set_lun(lun as u8, device.fd.as_fd(), device.cdrom, device.ro)?;
-        }
+            Ok(())
+        });
 
-        if gadget.create_config(config_name, &function_name)? {
-            debug!("Created mass storage config");
+        if let Err(e) = result {
+            debug!("Skipping gadget HAL cgroup {path:?}: {e}");
         }
     }
 
-    debug!("Applying config to USB controller: {controller:?}");
-    gadget.set_controller(Some(&controller))?;
+    for process in &processes {
+        debug!("Sending SIGCONT to gadget HAL process: {:?}", process.pid);
+
+        match util::pidfd_send_signal(&process.pidfd, Signal::CONT) {
+            Ok(()) => {}
+            // See find_gadget_hal().
+            Err(Errno::NOSYS) => {}
+            Err(e) => warn!("Failed to send SIGCONT to {:?}: {e}", process.pid),
+        }
+    }
 
     Ok(())
 }
 
-fn handle_get_mass_storage_request() -> Result<Vec<ActiveMassStorageDevice>> {
-    let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
-    let function_name = detect_function_name(&gadget)?;
-    let mut devices = vec![];
+/// Bind the daemon's socket. Whoever holds the socket owns the gadget HAL, so
+/// this is also used by the watchdog to check that no newer daemon has started.
+/// Returns None if the socket is still in use after `timeout`.
+fn bind_socket(timeout: Duration) -> Result<Option<UnixListener>> {
+    let start = Instant::now();
 
-    if let Some(function) = gadget.open_mass_storage_function(&function_name)? {
-        for lun in function.luns()? {
-            let (file, cdrom, ro) = function.get_lun(lun)?;
+    loop {
+        match UnixListener::bind_addr(&socket_addr()) {
+            Ok(l) => return Ok(Some(l)),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                if start.elapsed() >= timeout {
+                    return Ok(None);
+                }
 
-            // On Samsung devices, the mass storage gadget function cannot be
-            // recreated, so we leave it in an unconfigured state.
-            if let Some(file) = file {
-                devices.push(ActiveMassStorageDevice { file, cdrom, ro });
+                thread::sleep(Duration::from_millis(100));
             }
+            Err(e) => return Err(e).context("Failed to listen on domain socket"),
         }
     }
+}
 
-    Ok(devices)
+/// Handle to the watchdog process. Every time the daemon pauses the gadget HAL,
+/// it hands over the cgroups and processes to the watchdog under a new token
+/// and retracts them by token once they are resumed.
+struct Watchdog {
+    _child: Child,
+    stdin: Mutex<ChildStdin>,
+    next_token: AtomicU64,
 }
 
-fn handle_request(request: &Request) -> Response {
-    let ret = match request {
-        Request::GetFunctions(_) => handle_get_functions_request()
-            .map(|functions| Response::GetFunctions(GetFunctionsResponse { functions })),
-        Request::SetMassStorage(r) => handle_set_mass_storage_request(r)
-            .map(|()| Response::SetMassStorage(SetMassStorageResponse)),
-        Request::GetMassStorage(_) => handle_get_mass_storage_request()
-            .map(|devices| Response::GetMassStorage(GetMassStorageResponse { devices })),
-    };
+impl Watchdog {
+    /// Spawn a watchdog process that resumes the gadget HAL after this process
+    /// exits. This covers the cases where [`PausedGadgetHal`]'s destructor
+    /// never runs, like SIGKILL, the OOM killer, or a panic with
+    /// `panic=abort`. The watchdog waits for EOF on stdin, which happens when
+    /// the kernel closes the write end of the pipe as this process dies.
+    ///
+    /// The watchdog runs in its own process group so that it survives signals
+    /// sent to the daemon's process group. If every process in the daemon's
+    /// cgroup is killed, like when init stops a service, the watchdog dies too.
+    /// The gadget HAL then stays paused until the next daemon starts, which
+    /// resumes it before doing anything else.
+    ///
+    /// If `block_suspend` is set, CAP_BLOCK_SUSPEND is passed on to the
+    /// watchdog via the ambient set so that it can release the wake lock.
+    /// Otherwise, the capability would be lost when the watchdog is exec'd as
+    /// the system user.
+    fn spawn(block_suspend: bool) -> Result<Self> {
+        let raised = block_suspend
+            && rustix::thread::configure_capability_in_ambient_set(
+                CapabilitySet::BLOCK_SUSPEND,
+                true,
+            )
+            .inspect_err(|e| warn!("Wake lock will not be released if daemon crashes: {e}"))
+            .is_ok();
+
+        let child = process::Command::new("/proc/self/exe")
+            .args(env::args_os().skip(1))
+            .arg("--watchdog")
+            .stdin(Stdio::piped())
+            .process_group(0)
+            .spawn()
+            .context("Failed to spawn watchdog process");
+
+        // Nothing else that the daemon spawns should inherit the capability.
+        if raised {
+            rustix::thread::configure_capability_in_ambient_set(
+                CapabilitySet::BLOCK_SUSPEND,
+                false,
+            )
+            .context("Failed to remove CAP_BLOCK_SUSPEND from ambient set")?;
+        }
 
-    ret.unwrap_or_else(|e| {
-        warn!("{e:?}");
+        let mut child = child?;
+        let stdin = child.stdin.take().unwrap();
 
-        Response::Error(ErrorResponse {
-            message: format!("{e:?}"),
+        Ok(Self {
+            _child: child,
+            stdin: Mutex::new(stdin),
+            next_token: AtomicU64::new(0),
         })
-    })
-}
+    }
 
-fn handle_client(mut stream: UnixStream) -> Result<()> {
-    check_selinux()?;
-    negotiate_protocol(&mut stream)?;
+    fn send(&self, message: &str) {
+        // Each write is atomic as long as it is shorter than PIPE_BUF.
+        if let Err(e) = self.stdin.lock().unwrap().write_all(message.as_bytes()) {
+            warn!("Failed to send message to watchdog: {e}");
+        }
+    }
 
-    loop {
-        let request = match Request::from_socket(&mut stream) {
-            Ok(r) => r,
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
-            Err(e) => return Err(e).context("Failed to receive request"),
-        };
+    /// Hand over the gadget HAL processes and the cgroups that will be frozen
+    /// before they are paused. Returns the token for retracting them.
+    fn hand_over(&self, processes: &[Process], cgroups: &[&Path]) -> u64 {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let mut message = String::new();
 
-        debug!("Request: {request:?}");
+        for path in cgroups {
+            match path.to_str() {
+                Some(p) => message.push_str(&format!("pause {token} cgroup {p}\n")),
+                None => warn!("Not handing over non-UTF-8 cgroup: {path:?}"),
+            }
+        }
 
-        let response = handle_request(&request);
+        for process in processes {
+            message.push_str(&format!(
+                "pause {token} pid {}\n",
+                process.pid.as_raw_nonzero(),
+            ));
+        }
 
-        debug!("Response: {response:?}");
+        self.send(&message);
 
-        response
-            .to_socket(&mut stream)
-            .with_context(|| format!("Failed to send response: {response:?}"))?;
+        token
     }
-}
 
-fn drop_privileges() -> Result<()> {
-    // The only thing we need root level permissions for is chown'ing newly
-    // created files on configfs. Unlike other filesystems, newly created files
-    // on configfs are always owned by root:root. There was a patch from 2021 to
-    // fix this behavior, but it was never accepted.
-    // https://lore.kernel.org/lkml/20210123205516.2738060-1-zenczykowski@gmail.com/
-    //
-    // For ADB/MTP/etc., AOSP works around this by having an init script create
-    // the paths on configfs and chown them appropriately. This approach does
-    // not work for us because creating a LUN that's not associated with a file
-    // still results in a 0-sized device being advertised. This prevents some
-    // machines from booting from another mass storage device. Bootable devices
-    // is an important use case for MSD, so we're stuck with requiring elevated
-    // privileges.
-    //
-    // There are 2 ways the daemon can be run. If we're running runing as
-    // system:system, then the parent process is responsible for execve'ing with
-    // CAP_CHROOT allowed. If we're running as root:root, then we drop all
-    // capabilities besides CAP_CHROOT and drop privileges to system:system.
+    /// Retract everything handed over with `token` once it is resumed.
+    fn retract(&self, token: u64) {
+        self.send(&format!("resume {token}\n"));
+    }
+}
 
-    let system_uid = Uid::from_raw(1000);
-    let system_gid = Gid::from_raw(1000);
-    let real_uid = rustix::process::getuid();
-    let real_gid = rustix::process::getgid();
+/// Something that the daemon paused and handed over to the watchdog.
+enum HandedOver {
+    Cgroup(Cgroup),
+    Process(OwnedFd),
+}
 
-    let supplementary_groups = &[
-        // Android 10 emulator with sdcardfs.
-        Gid::from_raw(1015), // sdcard_rw
-        // Samsung with sdcardfs and LineageOS GSI with fuse-bpf.
-        Gid::from_raw(1023), // media_rw
-        Gid::from_raw(9997), // everybody
-    ];
+fn parse_watchdog_message(line: &str, paused: &mut BTreeMap<u64, Vec<HandedOver>>) -> Result<()> {
+    let (command, rest) = line
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("Invalid message: {line:?}"))?;
+    let (token, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+    let token = token
+        .parse::<u64>()
+        .with_context(|| format!("Invalid token: {line:?}"))?;
+
+    match (command, rest.split_once(' ')) {
+        ("resume", None) if rest.is_empty() => {
+            paused.remove(&token);
+        }
+        ("pause", Some(("cgroup", path))) => {
+            // This pins the cgroup in case it is recreated.
+            let cgroup = Cgroup::open(Path::new(path))
+                .with_context(|| format!("Failed to open cgroup: {path:?}"))?;
+            paused
+                .entry(token)
+                .or_default()
+                .push(HandedOver::Cgroup(cgroup));
+        }
+        ("pause", Some(("pid", pid))) => {
+            let pid = pid
+                .parse::<i32>()
+                .ok()
+                .and_then(Pid::from_raw)
+                .ok_or_else(|| anyhow!("Invalid PID: {line:?}"))?;
+
+            // See find_gadget_hal().
+            match util::pidfd_open(pid) {
+                Ok(pidfd) => paused
+                    .entry(token)
+                    .or_default()
+                    .push(HandedOver::Process(pidfd)),
+                Err(Errno::NOSYS) => {}
+                Err(e) => return Err(e).with_context(|| format!("Failed to open PID: {pid:?}")),
+            }
+        }
+        _ => bail!("Invalid message: {line:?}"),
+    }
 
-    if real_uid == system_uid && real_gid == system_gid {
-        let capability_set =
-            rustix::thread::capabilities(None).context("Failed to query capabilities")?;
+    Ok(())
+}
 
-        if !capability_set.effective.contains(CapabilitySet::CHOWN) {
-            bail!("CAP_CHOWN is required when running as system user");
+fn run_watchdog(cli: &DaemonCli) -> Result<()> {
+    // Check that the wake lock can be released before the daemon dies so that
+    // problems show up in the logs right away.
+    let wake_lock = cli.wake_lock.as_deref().and_then(|name| {
+        let capabilities = rustix::thread::capabilities(None)
+            .inspect_err(|e| warn!("Failed to query capabilities: {e}"))
+            .ok()?;
+        if !capabilities
+            .effective
+            .contains(CapabilitySet::BLOCK_SUSPEND)
+        {
+            warn!("CAP_BLOCK_SUSPEND is missing; wake lock will not be released if daemon crashes");
+            return None;
         }
-    } else if real_uid == Uid::ROOT && real_gid == Gid::ROOT {
-        rustix::thread::set_keep_capabilities(true)
-            .context("Failed to set keep capabilities flag")?;
 
-        debug!("uid={system_uid:?}, gid={system_gid:?}, groups={supplementary_groups:?}");
+        WakeLock::new(name)
+            .inspect_err(|e| warn!("Wake lock will not be released if daemon crashes: {e:?}"))
+            .ok()
+    });
 
-        rustix::thread::set_thread_groups(supplementary_groups)
-            .context("Failed to set supplementary groups")?;
-        rustix::thread::set_thread_res_gid(system_gid, system_gid, system_gid)
-            .context("Failed to switch GID to system group")?;
-        rustix::thread::set_thread_res_uid(system_uid, system_uid, system_uid)
-            .context("Failed to switch UID to system user")?;
-    } else {
-        bail!("Must run as root or system user, not {real_uid:?} {real_gid:?}");
+    let mut paused = BTreeMap::new();
+
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("Failed to read message from daemon: {e}");
+                break;
+            }
+        };
+
+        if let Err(e) = parse_watchdog_message(&line, &mut paused) {
+            warn!("Ignoring message from daemon: {e:?}");
+        }
     }
 
-    let capability_set = CapabilitySets {
-        effective: CapabilitySet::CHOWN,
-        permitted: CapabilitySet::CHOWN,
-        inheritable: CapabilitySet::empty(),
+    let paused = paused.into_values().flatten().collect::<Vec<_>>();
+    if paused.is_empty() && wake_lock.is_none() {
+        debug!("Daemon exited; nothing to clean up");
+        return Ok(());
+    }
+
+    // A newer daemon resumes the gadget HAL when it starts and may have paused
+    // it again since then.
+    let Some(_listener) = bind_socket(WATCHDOG_BIND_TIMEOUT)? else {
+        info!("Daemon exited, but another daemon is running; not cleaning up");
+        return Ok(());
     };
 
-    rustix::thread::set_capabilities(None, capability_set)
-        .context("Failed to drop capabilities")?;
+    if let Some(wake_lock) = wake_lock {
+        info!("Daemon exited; releasing wake lock");
+
+        match wake_lock.release_stale() {
+            Ok(true) => info!("Released wake lock: {}", wake_lock.name()),
+            Ok(false) => debug!("Wake lock was not held: {}", wake_lock.name()),
+            Err(e) => warn!("Failed to release wake lock: {e:?}"),
+        }
+    }
+
+    info!("Daemon exited; resuming gadget HAL");
+
+    for item in paused {
+        match item {
+            HandedOver::Cgroup(cgroup) => {
+                if let Err(e) = cgroup.set_frozen(false) {
+                    warn!("Failed to thaw cgroup: {e}");
+                }
+            }
+            HandedOver::Process(pidfd) => match util::pidfd_send_signal(&pidfd, Signal::CONT) {
+                // The process exited in the meantime.
+                Ok(()) | Err(Errno::SRCH) => {}
+                Err(e) => warn!("Failed to send SIGCONT to {pidfd:?}: {e}"),
+            },
+        }
+    }
 
     Ok(())
 }
 
-pub fn subcommand_daemon(_cli: &DaemonCli) -> Result<()> {
-    drop_privileges()?;
+/// Gadget HAL processes that are paused for as long as this is alive.
+struct PausedGadgetHal<'a> {
+    freezers: Vec<CgroupFreezer>,
+    stoppers: Vec<ProcessStopper>,
+    /// The watchdog and the token that the processes were handed over with.
+    watchdog: Option<(&'a Watchdog, u64)>,
+}
 
-    let listener =
-        UnixListener::bind_addr(&socket_addr()).context("Failed to listen on domain socket")?;
+impl<'a> PausedGadgetHal<'a> {
+    fn new(daemon: &'a Daemon) -> Result<Self> {
+        let cli = daemon.cli;
+        let domains = &cli.gadget_hal_domain;
 
-    thread::scope(|scope| -> Result<()> {
-        for stream in listener.incoming() {
-            let stream = stream.context("Failed to accept incoming connection")?;
-            let ucred = rustix::net::sockopt::socket_peercred(&stream)
-                .context("Failed to get socket peer credentials")?;
+        let processes = find_gadget_hal(domains)?;
+        if processes.is_empty() {
+            warn!("No gadget HAL process found: {domains:?} or {GADGET_HAL_PROCESS}*");
+        }
 
-            scope.spawn(move || {
-                let _span = info_span!(
-                    "peer",
-                    pid = ucred.pid.as_raw_nonzero(),
-                    uid = ucred.uid.as_raw(),
-                    gid = ucred.gid.as_raw(),
-                )
-                .entered();
+        for process in &processes {
+            debug!(
+                "Found gadget HAL process: {:?}: {:?}: {:?}",
+                process.pid, process.name, process.label,
+            );
+        }
 
-                if ucred.pid == rustix::process::getpid() {
-                    error!("SELinux rules are broken; able to connect to self");
-                    return;
-                }
+        let cgroups = match cli.stop_method {
+            StopMethod::Freezer => gadget_hal_cgroups(&processes),
+            StopMethod::Signal => vec![],
+        };
 
-                info!("Received connection");
+        // Everything is handed over first so that nothing is left paused if
+        // this process dies in the middle of pausing the gadget HAL.
+        let watchdog = daemon.watchdog.as_ref().map(|w| {
+            let paths = cgroups
+                .iter()
+                .map(|(p, _, _)| p.as_path())
+                .collect::<Vec<_>>();
+            (w, w.hand_over(&processes, &paths))
+        });
+
+        let mut result = Self {
+            freezers: vec![],
+            stoppers: vec![],
+            watchdog,
+        };
+        let mut frozen = vec![];
 
-                if let Err(e) = handle_client(stream) {
-                    error!("Thread failed: {e:?}");
+        for (path, cgroup, pids) in cgroups {
+            match CgroupFreezer::new(cgroup, FREEZE_TIMEOUT) {
+                Ok(f) => {
+                    result.freezers.push(f);
+                    frozen.extend(pids);
                 }
-            });
+                Err(e) => warn!("Cannot freeze cgroup {path:?}; falling back to SIGSTOP: {e}"),
+            }
         }
 
-        unreachable!()
-    })?;
+        for process in processes.into_iter().filter(|p| !frozen.contains(&p.pid)) {
+            match ProcessStopper::new(process.pidfd) {
+                Ok(s) => result.stoppers.push(s),
+                // See find_gadget_hal().
+                Err(Errno::NOSYS) => {}
+                Err(e) => return Err(e).context("Failed to stop gadget HAL process"),
+            }
+        }
 
-    Ok(())
+        Ok(result)
+    }
 }
 
-/// Run daemon.
-#[derive(Debug, Parser)]
-pub struct DaemonCli;
+impl Drop for PausedGadgetHal<'_> {
+    fn drop(&mut self) {
+        self.freezers.clear();
+        self.stoppers.clear();
+
+        if let Some((watchdog, token)) = self.watchdog {
+            watchdog.retract(token);
+        }
+    }
+}
+
+/// Log information about a device sent by a client and check that it can be
+/// used as the backing file for a LUN. Both regular files and block devices
+/// are supported, but the latter only if `allow_block_devices` is set.
+fn check_device(device: &MassStorageDevice, allow_block_devices: bool) -> Result<()> {
+    debug!("Checking device request: {device:?}");
+
+    let fd_path = format!("/proc/self/fd/{}", device.fd.as_raw_fd());
+
+    match fs::read_link(&fd_path) {
+        Ok(p) => debug!("- Path: {p:?}"),
+        Err(e) => warn!("- Path: <Unknown>: {e:?}"),
+    }
+
+    match util::fd_get_label(device.fd.as_fd()) {
+        Ok(l) => debug!("- Label: {l:?}"),
+        Err(e) => warn!("- Label: <Unknown>: {e:?}"),
+    }
+
+    let stat = rustix::fs::fstat(&device.fd)
+        .with_context(|| format!("Failed to stat file: {:?}", device.fd))?;
+    let file_type = FileType::from_raw_mode(stat.st_mode);
+
+    debug! {"- Type: {file_type:?}"};
+    debug! {"- Mode: {:o}", Mode::from_raw_mode(stat.st_mode)};
+    debug! {"- UID: {}", stat.st_uid};
+    debug! {"- GID: {}", stat.st_gid};
+
+    let (size, block_size) = match file_type {
+        FileType::RegularFile => {
+            let block_size = if device.cdrom {
+                CDROM_BLOCK_SIZE
+            } else {
+                DISK_BLOCK_SIZE
+            };
+
+            (stat.st_size as u64, block_size)
+        }
+        FileType::BlockDevice => {
+            if !allow_block_devices {
+                bail!("Block devices are not allowed: {:?}", device.fd);
+            }
+
+            let size = util::block_device_size(device.fd.as_fd())
+                .with_context(|| format!("Failed to get block device size: {:?}", device.fd))?;
+            let sector_size = rustix::fs::ioctl_blksszget(&device.fd)
+                .with_context(|| format!("Failed to get sector size: {:?}", device.fd))?;
+
+            debug! {"- Sector size: {sector_size}"};
+
+            // For disks, the kernel exposes the device's logical sector size
+            // to the host as is.
+            let block_size = if device.cdrom {
+                CDROM_BLOCK_SIZE
+            } else {
+                u64::from(sector_size)
+            };
+
+            if !sector_size.is_power_of_two()
+                || !(DISK_BLOCK_SIZE..=MAX_SECTOR_SIZE).contains(&u64::from(sector_size))
+                || !block_size.is_multiple_of(u64::from(sector_size))
+            {
+                bail!("Unsupported sector size: {:?}: {sector_size}", device.fd);
+            }
+
+            // Opening a block device exclusively fails if it is mounted or
+            // claimed by another driver, like device-mapper. The device is only
+            // claimed while it is checked since the kernel opens LUNs without
+            // claiming them.
+            File::options()
+                .read(true)
+                .custom_flags(OFlags::EXCL.bits() as i32)
+                .open(&fd_path)
+                .with_context(|| format!("Block device is in use: {:?}", device.fd))?;
+
+            (size, block_size)
+        }
+        _ => bail!(
+            "Not a regular file or block device: {:?}: {file_type:?}",
+            device.fd,
+        ),
+    };
+
+    debug! {"- Size: {size}"};
+
+    // The kernel reopens the file by path, so the access mode of the fd does
+    // not matter to it. However, this ensures that the client is actually
+    // allowed to write to the file.
+    let flags = rustix::fs::fcntl_getfl(&device.fd)
+        .with_context(|| format!("Failed to get file status flags: {:?}", device.fd))?;
+    let access_mode = flags & OFlags::ACCMODE;
+    if !device.ro && access_mode != OFlags::RDWR {
+        bail!("File is not opened for writing: {:?}", device.fd);
+    }
+
+    // A file that is still open for writing, like an incomplete download,
+    // should not be exposed read-only. This cannot be checked if the client's
+    // own fd is writable because that counts as a writer too.
+    if file_type == FileType::RegularFile && access_mode == OFlags::RDONLY {
+        match util::has_writers(device.fd.as_fd()) {
+            Ok(true) => bail!(
+                "File is open for writing by another process: {:?}",
+                device.fd,
+            ),
+            Ok(false) => {}
+            Err(e) => warn!("Failed to check for writers: {:?}: {e}", device.fd),
+        }
+    }
+
+    // The kernel silently ignores trailing partial blocks.
+    if device.cdrom {
+        if !size.is_multiple_of(block_size) {
+            bail!(
+                "CD-ROM size is not a multiple of {block_size}: {:?}: {size}",
+                device.fd,
+            );
+        }
+    } else if size == 0 || !size.is_multiple_of(block_size) {
+        bail!(
+            "Disk size is not a non-zero multiple of {block_size}: {:?}: {size}",
+            device.fd,
+        );
+    }
+
+    // The kernel silently truncates longer strings.
+    if device.inquiry_string.len() > INQUIRY_STRING_MAX_LEN {
+        bail!(
+            "Inquiry string exceeds {INQUIRY_STRING_MAX_LEN} bytes: {:?}",
+            device.inquiry_string,
+        );
+    } else if !device
+        .inquiry_string
+        .bytes()
+        .all(|b| b.is_ascii_graphic() || b == b' ')
+    {
+        bail!(
+            "Inquiry string contains non-printable ASCII characters: {:?}",
+            device.inquiry_string,
+        );
+    }
+
+    Ok(())
+}
+
+/// Check that the devices fit within the kernel's LUN limit and that no file is
+/// exported read-write by more than one LUN. Neither the kernel nor the host
+/// coordinate writes between LUNs, so the latter would corrupt the file.
+/// `existing` are the LUNs that are kept as is.
+fn check_devices<'a>(
+    devices: &[&MassStorageDevice],
+    existing: impl IntoIterator<Item = &'a LunRecord>,
+) -> Result<()> {
+    let mut count = devices.len();
+    let mut files = devices
+        .iter()
+        .map(|d| (d.fd.as_fd(), d.ro))
+        .collect::<Vec<_>>();
+
+    for record in existing {
+        count += 1;
+
+        if let Some(fd) = &record.fd {
+            files.push((fd.as_fd(), record.attrs.ro));
+        }
+    }
+
+    if count > MAX_LUNS {
+        bail!("Number of LUNs exceeds {MAX_LUNS}: {count}");
+    }
+
+    let mut writable = BTreeSet::new();
+
+    for (fd, ro) in files {
+        if ro {
+            continue;
+        }
+
+        let id = util::file_id(fd).with_context(|| format!("Failed to stat file: {fd:?}"))?;
+        if !writable.insert(id) {
+            bail!("File is exported read-write more than once: {fd:?}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Map a path on Android's FUSE filesystem for emulated storage to the
+/// equivalent path on the lower filesystem. Depending on the mount namespace,
+/// the FUSE filesystem is visible at either `/storage/emulated` or
+/// `/mnt/user/<user>/emulated`.
+fn fuse_lower_path(path: &Path) -> Option<PathBuf> {
+    let rest = match path.strip_prefix("/storage/emulated") {
+        Ok(p) => p,
+        Err(_) => {
+            let mut components = path.strip_prefix("/mnt/user").ok()?.components();
+            components.next()?;
+            components.as_path().strip_prefix("emulated").ok()?
+        }
+    };
+
+    let mut components = rest.components();
+    let user = components.next()?.as_os_str();
+    if !user.to_str()?.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let rest = components.as_path();
+    if !rest.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+
+    Some(Path::new(MEDIA_ROOT).join(user).join(rest))
+}
+
+/// Open the file on the lower filesystem that backs a file on Android's FUSE
+/// filesystem. Returns None if the file is not on FUSE.
+///
+/// The client's fd proves that the FUSE daemon granted it access to the file.
+/// The lower file is opened with no more access than the fd has and must be
+/// the same file.
+fn open_fuse_lower_file(device: &MassStorageDevice) -> Result<Option<OwnedFd>> {
+    let statfs = rustix::fs::fstatfs(&device.fd)
+        .with_context(|| format!("Failed to stat filesystem: {:?}", device.fd))?;
+    // f_type has different types on different architectures.
+    if statfs.f_type as u32 != util::FUSE_SUPER_MAGIC {
+        return Ok(None);
+    }
+
+    let fd_path = format!("/proc/self/fd/{}", device.fd.as_raw_fd());
+    let path =
+        fs::read_link(&fd_path).with_context(|| format!("Failed to read link: {fd_path:?}"))?;
+    let Some(lower_path) = fuse_lower_path(&path) else {
+        debug!("FUSE file is not on emulated storage: {path:?}");
+        return Ok(None);
+    };
+
+    let flags = rustix::fs::fcntl_getfl(&device.fd)
+        .with_context(|| format!("Failed to get file status flags: {:?}", device.fd))?;
+    let writable = (flags & OFlags::ACCMODE) == OFlags::RDWR;
+
+    let file = File::options()
+        .read(true)
+        .write(writable)
+        .custom_flags(OFlags::NOFOLLOW.bits() as i32)
+        .open(&lower_path)
+        .with_context(|| format!("Failed to open file: {lower_path:?}"))?;
+
+    let lower_statfs = rustix::fs::fstatfs(&file)
+        .with_context(|| format!("Failed to stat filesystem: {lower_path:?}"))?;
+    if lower_statfs.f_type as u32 == util::FUSE_SUPER_MAGIC {
+        bail!("Lower file is also on FUSE: {lower_path:?}");
+    }
+
+    // The FUSE daemon reports the lower file's inode number.
+    let stat = rustix::fs::fstat(&device.fd)
+        .with_context(|| format!("Failed to stat file: {:?}", device.fd))?;
+    let lower_stat =
+        rustix::fs::fstat(&file).with_context(|| format!("Failed to stat file: {lower_path:?}"))?;
+    if stat.st_ino != lower_stat.st_ino
+        || stat.st_size != lower_stat.st_size
+        || stat.st_mode != lower_stat.st_mode
+    {
+        bail!("Lower file does not match FUSE file: {lower_path:?} != {path:?}");
+    }
+
+    debug!("Bypassing FUSE: {path:?} -> {lower_path:?}");
+
+    Ok(Some(file.into()))
+}
+
+/// Check if the kernel will be able to reopen the file via procfs. This is not
+/// possible for proxy file descriptors from
+/// `StorageManager.openProxyFileDescriptor()`.
+fn can_reopen(device: &MassStorageDevice) -> bool {
+    let fd_path = format!("/proc/self/fd/{}", device.fd.as_raw_fd());
+
+    match File::options().read(true).write(!device.ro).open(&fd_path) {
+        Ok(_) => true,
+        Err(e) => {
+            debug!("Cannot reopen file: {fd_path:?}: {e}");
+            false
+        }
+    }
+}
+
+/// Lock the device's file to keep other cooperating processes, including the
+/// client, from using it at the same time. Read-write files are locked
+/// exclusively. Returns None if the filesystem does not support locking.
+fn lock_device(device: &MassStorageDevice) -> Result<Option<FileLock>> {
+    match FileLock::new(device.fd.as_fd(), !device.ro) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(e) => match Errno::from_io_error(&e) {
+            Some(Errno::WOULDBLOCK) => {
+                bail!("File is locked by another process: {:?}", device.fd);
+            }
+            Some(Errno::NOLCK | Errno::OPNOTSUPP) => {
+                warn!("Filesystem does not support locking: {:?}: {e}", device.fd);
+                Ok(None)
+            }
+            _ => Err(e).with_context(|| format!("Failed to lock file: {:?}", device.fd)),
+        },
+    }
+}
+
+/// Resources that are acquired for a device before the gadget is touched and
+/// are kept for as long as a LUN uses the device.
+struct DeviceResources {
+    lock: Option<FileLock>,
+    /// If set, the LUN is backed by this instead of the device's file.
+    loop_device: Option<LoopDevice>,
+}
+
+/// Associate an existing LUN with a device and return the daemon's record of
+/// it.
+#[allow(clippy::too_many_arguments)]
+fn set_lun(
+    function: &MassStorageFunction,
+    lun: u8,
+    device: &MassStorageDevice,
+    resources: DeviceResources,
+    lease: LunLease,
+    idle_timeout: Option<Duration>,
+    one_shot: Option<OneShot>,
+    owner: &LunOwner,
+) -> Result<LunRecord> {
+    let attrs = LunAttrs {
+        cdrom: device.cdrom,
+        ro: device.ro,
+        removable: device.removable,
+        nofua: device.nofua,
+        inquiry_string: device.inquiry_string.clone(),
+    };
+
+    let DeviceResources { lock, loop_device } = resources;
+
+    let backing_fd = match &loop_device {
+        Some(l) => {
+            debug!("Associating LUN #{lun} with {device:?} via {:?}", l.path());
+            l.as_fd()
+        }
+        None => {
+            debug!("Associating LUN #{lun} with {device:?}");
+            device.fd.as_fd()
+        }
+    };
+    function.set_lun(lun, backing_fd, &attrs)?;
+
+    let (file, attrs) = function.get_lun(lun)?;
+    let file = file.ok_or_else(|| anyhow!("LUN #{lun} has no file after being set"))?;
+    let fd = device
+        .fd
+        .try_clone()
+        .with_context(|| format!("Failed to duplicate fd: {:?}", device.fd))?;
+
+    let stat =
+        rustix::fs::fstat(&fd).with_context(|| format!("Failed to stat file: {:?}", device.fd))?;
+    let size = (FileType::from_raw_mode(stat.st_mode) == FileType::RegularFile)
+        .then_some(stat.st_size as u64);
+
+    let stats_base = BlockStats::read(backing_fd)
+        .inspect_err(|e| warn!("Failed to read I/O statistics for LUN #{lun}: {e}"))
+        .ok()
+        .flatten();
+
+    Ok(LunRecord {
+        file,
+        attrs,
+        state: LunState::Active,
+        fd: Some(fd),
+        size,
+        lock,
+        loop_device,
+        last_read: None,
+        last_write: None,
+        stats_base,
+        lease,
+        idle_timeout,
+        configured: BootInstant::now(),
+        one_shot,
+        owner: owner.clone(),
+    })
+}
+
+/// Check that the descriptor overrides can be applied to the gadget.
+fn check_descriptors(overrides: &DescriptorOverrides) -> Result<()> {
+    for s in [
+        &overrides.manufacturer,
+        &overrides.product,
+        &overrides.serial_number,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if s.len() > USB_MAX_STRING_LEN {
+            bail!("Descriptor string exceeds {USB_MAX_STRING_LEN} bytes: {s:?}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Override the gadget's device descriptors. The original descriptors are
+/// saved the first time so that they can be restored later.
+fn override_descriptors(
+    gadget: &UsbGadget,
+    state: &mut GadgetState,
+    overrides: &DescriptorOverrides,
+) -> Result<()> {
+    let original = match &state.original_descriptors {
+        Some(d) => d.clone(),
+        None => {
+            let d = gadget.descriptors()?;
+            debug!("Saving original descriptors: {d:?}");
+            state.original_descriptors = Some(d.clone());
+            d
+        }
+    };
+
+    let descriptors = GadgetDescriptors {
+        id_vendor: overrides.id_vendor.unwrap_or(original.id_vendor),
+        id_product: overrides.id_product.unwrap_or(original.id_product),
+        bcd_device: overrides.bcd_device.unwrap_or(original.bcd_device),
+        manufacturer: overrides
+            .manufacturer
+            .clone()
+            .unwrap_or(original.manufacturer),
+        product: overrides.product.clone().unwrap_or(original.product),
+        serial_number: overrides
+            .serial_number
+            .clone()
+            .unwrap_or(original.serial_number),
+    };
+
+    debug!("Overriding descriptors: {descriptors:?}");
+    gadget.set_descriptors(&descriptors)
+}
+
+/// Restore the gadget's original device descriptors if they were overridden.
+fn restore_descriptors(gadget: &UsbGadget, state: &mut GadgetState) -> Result<()> {
+    if let Some(descriptors) = &state.original_descriptors {
+        debug!("Restoring original descriptors: {descriptors:?}");
+        gadget.set_descriptors(descriptors)?;
+        state.original_descriptors = None;
+    }
+
+    Ok(())
+}
+
+/// Check that the kernel supports limiting the gadget's maximum speed.
+fn check_max_speed(gadget: &UsbGadget) -> Result<()> {
+    if gadget.max_speed()?.is_none() {
+        bail!("Kernel does not support limiting the gadget speed");
+    }
+
+    Ok(())
+}
+
+/// Limit the gadget's maximum speed. The original maximum speed is saved the
+/// first time so that it can be restored later.
+fn limit_max_speed(gadget: &UsbGadget, state: &mut GadgetState, limit: SpeedLimit) -> Result<()> {
+    if state.original_max_speed.is_none() {
+        let Some(speed) = gadget.max_speed()? else {
+            bail!("Kernel does not support limiting the gadget speed");
+        };
+
+        debug!("Saving original max speed: {speed}");
+        state.original_max_speed = Some(speed);
+    }
+
+    debug!("Limiting max speed: {}", limit.as_str());
+    gadget.set_max_speed(limit.as_str())
+}
+
+/// Restore the gadget's original maximum speed if it was limited.
+fn restore_max_speed(gadget: &UsbGadget, state: &mut GadgetState) -> Result<()> {
+    if let Some(speed) = &state.original_max_speed {
+        debug!("Restoring original max speed: {speed}");
+        gadget.set_max_speed(speed)?;
+        state.original_max_speed = None;
+    }
+
+    Ok(())
+}
+
+/// Replace all LUNs with the requested devices. The LUNs are cleared if no
+/// devices are specified. The new LUNs are owned by `owner`.
+fn configure_mass_storage(
+    daemon: &Daemon,
+    state: &mut GadgetState,
+    request: &SetMassStorageRequest,
+    lease: LunLease,
+    owner: &LunOwner,
+) -> Result<()> {
+    let cli = daemon.cli;
+    let idle_timeout = daemon.idle_timeout(request.idle_timeout_ms);
+    let one_shot = daemon.one_shot(request.one_shot)?;
+
+    debug!("Configuring {} LUNs for {owner:?}", request.devices.len());
+
+    let lower_devices = request
+        .devices
+        .iter()
+        .map(|d| daemon.bypass_fuse(d))
+        .collect::<Vec<_>>();
+    let devices = request
+        .devices
+        .iter()
+        .zip(&lower_devices)
+        .map(|(d, l)| l.as_ref().unwrap_or(d))
+        .collect::<Vec<_>>();
+
+    for device in &devices {
+        check_device(device, cli.allow_block_devices)?;
+    }
+
+    // All existing LUNs are replaced.
+    check_devices(&devices, [])?;
+    check_descriptors(&request.descriptors)?;
+
+    let config_name = OsStr::new(CONFIG_NAME);
+    let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
+    let function_name = detect_function_name(&gadget)?;
+
+    if request.max_speed.is_some() && !devices.is_empty() {
+        check_max_speed(&gadget)?;
+    }
+
+    // We need to pause this process while we make our changes to prevent it
+    // from constantly trying to ensure that UDC is set to the expected value.
+    // Stopping the `vendor.usb-gadget-hal` init service would be cleaner, but
+    // does not work because the HAL fails restore its state properly after it
+    // starts back up, causing UDC to be cleared every time the device is
+    // unplugged.
+    let _paused_gadget_hal = PausedGadgetHal::new(daemon)?;
+
+    let Some(controller) = usb_controller()? else {
+        bail!("Cannot determine ID of USB controller");
+    };
+
+    // The devices are prepared last so that the replaced LUNs are not unlocked
+    // when any of the steps above fail.
+    let replaced = state.luns.keys().copied().collect::<Vec<_>>();
+    let mut resources = daemon
+        .prepare_devices(state, &devices, &replaced)?
+        .into_iter();
+
+    debug!("Disassociating gadget config from controller");
+    gadget.set_controller(None)?;
+
+    if gadget.delete_config(config_name)? {
+        debug!("Deleted old mass storage config");
+    }
+
+    // Extra LUNs must be deleted first, but lun.0 cannot be deleted. The
+    // records are only dropped once the kernel no longer uses the LUNs.
+    if let Some(function) = gadget.open_mass_storage_function(&function_name)? {
+        for lun in function.luns()? {
+            if lun == 0 {
+                function.clear_lun(lun)?;
+                debug!("Unregistered LUN #{lun}");
+            } else if function.delete_lun(lun)? {
+                debug!("Deleted LUN #{lun}");
+            }
+
+            state.luns.remove(&lun);
+        }
+    }
+
+    // Any remaining records are for LUNs that no longer exist.
+    state.luns.clear();
+
+    // On Samsung devices, mass storage gadget functions cannot be recreated.
+    if function_name == FUNCTION_NAME_DEFAULT && gadget.delete_function(&function_name)? {
+        debug!("Deleted old mass storage function");
+    }
+
+    if !devices.is_empty() {
+        if gadget.create_function(&function_name)? {
+            debug!("Created mass storage function");
+        }
+
+        let function = gadget
+            .open_mass_storage_function(&function_name)?
+            .ok_or_else(|| anyhow!("Newly created function does not exist: {function_name:?}"))?;
+
+        // The function may have been preserved from a previous configuration.
+        debug!("Setting stall: {}", request.stall);
+        function.set_stall(request.stall)?;
+
+        for (lun, device) in devices.iter().enumerate() {
+            // lun.0 exists by default.
+            if lun > 0 && function.create_lun(lun as u8)? {
+                debug!("Created LUN #{lun}");
+            }
+
+            let record = set_lun(
+                &function,
+                lun as u8,
+                device,
+                resources.next().unwrap(),
+                lease,
+                idle_timeout,
+                one_shot,
+                owner,
+            )?;
+            state.luns.insert(lun as u8, record);
+        }
+
+        if gadget.create_config(config_name, &function_name)? {
+            debug!("Created mass storage config");
+        }
+    }
+
+    if devices.is_empty() || request.descriptors.is_empty() {
+        restore_descriptors(&gadget, state)?;
+    } else {
+        override_descriptors(&gadget, state, &request.descriptors)?;
+    }
+
+    match request.max_speed {
+        Some(limit) if !devices.is_empty() => limit_max_speed(&gadget, state, limit)?,
+        _ => restore_max_speed(&gadget, state)?,
+    }
+
+    state.gadget_settings = if devices.is_empty() {
+        PersistedGadget::default()
+    } else {
+        PersistedGadget {
+            stall: request.stall,
+            descriptors: request.descriptors.clone(),
+            max_speed: request.max_speed,
+        }
+    };
+
+    debug!("Applying config to USB controller: {controller:?}");
+    gadget.set_controller(Some(&controller))?;
+
+    // Rebinding makes the host reconfigure the device, which should not count
+    // towards the reset limit of one-shot LUNs. This also supersedes any reset
+    // that was still pending.
+    state.ignore_resets_until = Some(Instant::now() + RESET_SETTLE_TIME);
+    state.reset_pending_since = None;
+
+    Ok(())
+}
+
+/// Modify the LUNs of the mass storage function without touching the other
+/// LUNs. The kernel does not allow LUNs to be created or deleted while the
+/// function is part of a config, so the config is temporarily removed. It is
+/// only recreated if any LUNs still have a backing file afterwards.
+fn modify_mass_storage<T>(
+    daemon: &Daemon,
+    state: &mut GadgetState,
+    f: impl FnOnce(&MassStorageFunction, &mut GadgetState) -> Result<T>,
+) -> Result<T> {
+    let config_name = OsStr::new(CONFIG_NAME);
+    let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
+    let function_name = detect_function_name(&gadget)?;
+
+    // See configure_mass_storage().
+    let _paused_gadget_hal = PausedGadgetHal::new(daemon)?;
+
+    let Some(controller) = usb_controller()? else {
+        bail!("Cannot determine ID of USB controller");
+    };
+
+    debug!("Disassociating gadget config from controller");
+    gadget.set_controller(None)?;
+
+    if gadget.delete_config(config_name)? {
+        debug!("Deleted old mass storage config");
+    }
+
+    if gadget.create_function(&function_name)? {
+        debug!("Created mass storage function");
+    }
+
+    let function = gadget
+        .open_mass_storage_function(&function_name)?
+        .ok_or_else(|| anyhow!("Newly created function does not exist: {function_name:?}"))?;
+
+    // The remaining LUNs must be restored even if the modification failed.
+    let ret = f(&function, state);
+
+    let mut in_use = !state.luns.is_empty();
+    for lun in function.luns()? {
+        in_use |= function.get_lun(lun)?.0.is_some();
+    }
+
+    if in_use {
+        if gadget.create_config(config_name, &function_name)? {
+            debug!("Created mass storage config");
+        }
+    } else {
+        restore_descriptors(&gadget, state)?;
+        restore_max_speed(&gadget, state)?;
+        state.gadget_settings = PersistedGadget::default();
+    }
+
+    debug!("Applying config to USB controller: {controller:?}");
+    gadget.set_controller(Some(&controller))?;
+
+    // See configure_mass_storage().
+    state.ignore_resets_until = Some(Instant::now() + RESET_SETTLE_TIME);
+    state.reset_pending_since = None;
+
+    ret
+}
+
+/// Delete the specified LUNs. lun.0 cannot be deleted, so it is cleared instead.
+fn remove_luns(daemon: &Daemon, state: &mut GadgetState, luns: &[u8]) -> Result<()> {
+    modify_mass_storage(daemon, state, |function, state| {
+        for &lun in luns {
+            if lun == 0 {
+                function.clear_lun(lun)?;
+                debug!("Unregistered LUN #{lun}");
+            } else if function.delete_lun(lun)? {
+                debug!("Deleted LUN #{lun}");
+            }
+
+            state.luns.remove(&lun);
+        }
+
+        Ok(())
+    })
+}
+
+fn get_lun_lease(session: &Session, lease: Lease) -> Result<LunLease> {
+    let lease = match lease {
+        Lease::None => LunLease::None,
+        Lease::Connection => LunLease::Connection(session.id),
+        Lease::Timeout { timeout_ms } => {
+            if timeout_ms == 0 {
+                bail!("Lease timeout must be non-zero");
+            }
+
+            let timeout = Duration::from_millis(timeout_ms.into());
+
+            LunLease::Timeout {
+                timeout,
+                deadline: BootInstant::now() + timeout,
+            }
+        }
+    };
+
+    Ok(lease)
+}
+
+fn handle_set_mass_storage_request(
+    daemon: &Daemon,
+    session: &Session,
+    request: &SetMassStorageRequest,
+) -> Result<()> {
+    let lease = get_lun_lease(session, request.lease)?;
+
+    let mut state = daemon.gadget.lock().unwrap();
+    let luns = state.luns.keys().copied().collect::<Vec<_>>();
+    daemon.check_lun_owner(&state, &luns, session, request.force)?;
+
+    configure_mass_storage(daemon, &mut state, request, lease, &session.owner)
+}
+
+fn handle_add_lun_request(
+    daemon: &Daemon,
+    session: &Session,
+    request: &AddLunRequest,
+) -> Result<u8> {
+    let lease = get_lun_lease(session, request.lease)?;
+    let idle_timeout = daemon.idle_timeout(request.idle_timeout_ms);
+    let one_shot = daemon.one_shot(request.one_shot)?;
+
+    let lower_device = daemon.bypass_fuse(&request.device);
+    let device = lower_device.as_ref().unwrap_or(&request.device);
+
+    check_device(device, daemon.cli.allow_block_devices)?;
+
+    let mut state = daemon.gadget.lock().unwrap();
+    check_devices(&[device], state.luns.values())?;
+
+    let resources = daemon
+        .prepare_devices(&mut state, &[device], &[])?
+        .pop()
+        .unwrap();
+
+    modify_mass_storage(daemon, &mut state, |function, state| {
+        let existing = function.luns()?;
+
+        // Reuse the first LUN that has no backing file. Otherwise, create a
+        // new LUN after the last one.
+        let mut lun = None;
+        for n in 0..MAX_LUNS as u8 {
+            if state.luns.contains_key(&n) {
+                continue;
+            } else if !existing.contains(&n) {
+                if function.create_lun(n)? {
+                    debug!("Created LUN #{n}");
+                }
+            } else if function.get_lun(n)?.0.is_some() {
+                continue;
+            }
+
+            lun = Some(n);
+            break;
+        }
+
+        let Some(lun) = lun else {
+            bail!("No free LUNs available");
+        };
+
+        match set_lun(
+            function,
+            lun,
+            device,
+            resources,
+            lease,
+            idle_timeout,
+            one_shot,
+            &session.owner,
+        ) {
+            Ok(record) => {
+                state.luns.insert(lun, record);
+                Ok(lun)
+            }
+            Err(e) => {
+                // Don't leave an empty LUN behind.
+                if lun != 0 && !existing.contains(&lun) {
+                    let _ = function.delete_lun(lun);
+                }
+
+                Err(e)
+            }
+        }
+    })
+}
+
+fn handle_remove_lun_request(
+    daemon: &Daemon,
+    session: &Session,
+    request: &RemoveLunRequest,
+) -> Result<()> {
+    let mut state = daemon.gadget.lock().unwrap();
+    daemon.check_lun_owner(&state, &[request.lun], session, request.force)?;
+
+    if !state.luns.contains_key(&request.lun) {
+        let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
+        let function_name = detect_function_name(&gadget)?;
+        let exists = match gadget.open_mass_storage_function(&function_name)? {
+            Some(function) => function.luns()?.contains(&request.lun),
+            None => false,
+        };
+
+        if !exists {
+            bail!("LUN #{} does not exist", request.lun);
+        }
+    }
+
+    remove_luns(daemon, &mut state, &[request.lun])
+}
+
+fn handle_change_media_request(
+    daemon: &Daemon,
+    session: &Session,
+    request: &ChangeMediaRequest,
+) -> Result<()> {
+    let lun = request.lun;
+
+    let lower_device = daemon.bypass_fuse(&request.device);
+    let device = lower_device.as_ref().unwrap_or(&request.device);
+
+    check_device(device, daemon.cli.allow_block_devices)?;
+
+    let mut state = daemon.gadget.lock().unwrap();
+    daemon.check_lun_owner(&state, &[lun], session, request.force)?;
+    check_devices(
+        &[device],
+        state
+            .luns
+            .iter()
+            .filter(|(l, _)| **l != lun)
+            .map(|(_, r)| r),
+    )?;
+
+    let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
+    let function_name = detect_function_name(&gadget)?;
+    let function = match gadget.open_mass_storage_function(&function_name)? {
+        Some(f) if f.luns()?.contains(&lun) => f,
+        _ => bail!("LUN #{lun} does not exist"),
+    };
+
+    // The host does not expect the media of a non-removable disk to change
+    // underneath it.
+    if !function.get_lun(lun)?.1.removable {
+        bail!("LUN #{lun} is not removable");
+    }
+
+    let resources = daemon
+        .prepare_devices(&mut state, &[device], &[lun])?
+        .pop()
+        .unwrap();
+
+    // Unlike adding or removing LUNs, this does not require the gadget to be
+    // unbound, so the host only sees the media change.
+    debug!("Ejecting media from LUN #{lun}");
+    if let Err(e) = function.forced_eject(lun) {
+        if let Some(record) = state.luns.get_mut(&lun) {
+            record.relock(lun);
+        }
+
+        return Err(e);
+    }
+
+    // The media belongs to the same LUN, so its automatic clearing conditions
+    // and owner are kept.
+    let (lease, idle_timeout, one_shot, owner) = match state.luns.get(&lun) {
+        Some(r) => (r.lease, r.idle_timeout, r.one_shot, r.owner.clone()),
+        None => (
+            LunLease::None,
+            daemon.idle_timeout(0),
+            None,
+            session.owner.clone(),
+        ),
+    };
+
+    match set_lun(
+        &function,
+        lun,
+        device,
+        resources,
+        lease,
+        idle_timeout,
+        one_shot,
+        &owner,
+    ) {
+        Ok(record) => {
+            state.luns.insert(lun, record);
+            Ok(())
+        }
+        Err(e) => {
+            // Put the old media back instead of leaving the LUN empty.
+            if let Some(record) = state.luns.get_mut(&lun)
+                && record.state == LunState::Active
+            {
+                let ret = match record.backing_fd() {
+                    Some(fd) => function.set_lun(lun, fd, &record.attrs),
+                    None => Err(anyhow!("No reference to old media")),
+                };
+
+                match ret {
+                    Ok(()) => {
+                        info!("Restored old media of LUN #{lun}: {:?}", record.file);
+                        record.relock(lun);
+                    }
+                    Err(restore_e) => {
+                        warn!("Failed to restore old media of LUN #{lun}: {restore_e:?}");
+                        state.luns.remove(&lun);
+                    }
+                }
+            }
+
+            Err(e)
+        }
+    }
+}
+
+fn handle_renew_lease_request(daemon: &Daemon, session: &Session) -> Result<()> {
+    let mut state = daemon.gadget.lock().unwrap();
+    let now = BootInstant::now();
+
+    // A client can only keep its own LUNs alive, even if ownership is not
+    // enforced. Otherwise, a client could keep another client's abandoned LUNs
+    // around forever.
+    let leases = state
+        .luns
+        .iter_mut()
+        .filter(|(_, r)| r.owner == session.owner)
+        .filter_map(|(lun, r)| match &mut r.lease {
+            LunLease::Timeout { timeout, deadline } => Some((*lun, *timeout, deadline)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if leases.is_empty() {
+        bail!("No LUNs owned by this client have a renewable lease");
+    }
+
+    // Nothing is renewed if any lease has expired so that a failed request has
+    // no effect. The LUN will be removed by the next poll.
+    if let Some((lun, _, _)) = leases.iter().find(|(_, _, d)| **d <= now) {
+        bail!("Lease for LUN #{lun} has already expired");
+    }
+
+    for (_, timeout, deadline) in leases {
+        *deadline = now + timeout;
+    }
+
+    Ok(())
+}
+
+fn idle_timeout_ms(record: &LunRecord) -> u32 {
+    record
+        .idle_timeout
+        .map(|t| t.as_millis() as u32)
+        .unwrap_or_default()
+}
+
+fn elapsed_ms(instant: Option<BootInstant>) -> Option<u64> {
+    instant.map(|t| t.elapsed().as_millis() as u64)
+}
+
+fn handle_get_mass_storage_request(daemon: &Daemon) -> Result<Vec<ActiveMassStorageDevice>> {
+    let mut state = daemon.gadget.lock().unwrap();
+    daemon.check_ejected_luns(&mut state)?;
+
+    let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
+    let function_name = detect_function_name(&gadget)?;
+    let mut devices = vec![];
+
+    if let Some(function) = gadget.open_mass_storage_function(&function_name)? {
+        for lun in function.luns()? {
+            let (file, attrs) = function.get_lun(lun)?;
+            let record = state.luns.get(&lun);
+            let stats = record.and_then(|r| r.io_stats());
+
+            if let Some(file) = file {
+                devices.push(ActiveMassStorageDevice {
+                    lun,
+                    file,
+                    cdrom: attrs.cdrom,
+                    ro: attrs.ro,
+                    removable: attrs.removable,
+                    nofua: attrs.nofua,
+                    inquiry_string: attrs.inquiry_string,
+                    state: LunState::Active,
+                    owner: record.map(|r| r.owner.clone()),
+                    idle_timeout_ms: record.map(idle_timeout_ms).unwrap_or_default(),
+                    idle_ms: record
+                        .map(|r| r.idle_duration(state.last_activity).as_millis() as u64)
+                        .unwrap_or_default(),
+                    last_read_ms: record.and_then(|r| elapsed_ms(r.last_read)),
+                    last_write_ms: record.and_then(|r| elapsed_ms(r.last_write)),
+                    read_bytes: stats.map(|s| s.read_bytes),
+                    written_bytes: stats.map(|s| s.written_bytes),
+                });
+            } else if let Some(record) = record
+                && record.state != LunState::Active
+            {
+                devices.push(ActiveMassStorageDevice {
+                    lun,
+                    file: record.file.clone(),
+                    cdrom: record.attrs.cdrom,
+                    ro: record.attrs.ro,
+                    removable: record.attrs.removable,
+                    nofua: record.attrs.nofua,
+                    inquiry_string: record.attrs.inquiry_string.clone(),
+                    state: record.state,
+                    owner: Some(record.owner.clone()),
+                    idle_timeout_ms: idle_timeout_ms(record),
+                    idle_ms: record.idle_duration(state.last_activity).as_millis() as u64,
+                    last_read_ms: elapsed_ms(record.last_read),
+                    last_write_ms: elapsed_ms(record.last_write),
+                    read_bytes: stats.map(|s| s.read_bytes),
+                    written_bytes: stats.map(|s| s.written_bytes),
+                });
+            }
+
+            // Otherwise, the LUN was never configured. On Samsung devices, the
+            // mass storage gadget function cannot be recreated, so we leave it
+            // in an unconfigured state.
+        }
+    }
+
+    Ok(devices)
+}
+
+fn handle_get_controller_state_request(daemon: &Daemon) -> Result<GetControllerStateResponse> {
+    let Some(controller) = usb_controller()? else {
+        bail!("Cannot determine ID of USB controller");
+    };
+
+    let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
+    let bound_controller = gadget.controller()?;
+    let udc = UsbController::new(&controller)?;
+    let host = daemon.host.lock().unwrap().clone();
+
+    Ok(GetControllerStateResponse {
+        controller,
+        bound_controller,
+        state: udc.state()?,
+        current_speed: udc.current_speed()?,
+        maximum_speed: udc.maximum_speed()?,
+        gadget_max_speed: gadget.max_speed()?,
+        wake_lock_held: daemon.wake_lock.as_ref().map(|w| w.is_held()),
+        is_otg: udc.is_otg()?,
+        host_state: host.as_ref().map(|h| h.state()),
+        host_state_duration_ms: host
+            .as_ref()
+            .map(|h| h.duration().as_millis() as u64)
+            .unwrap_or_default(),
+        host_connect_count: host.as_ref().map(|h| h.connect_count()).unwrap_or_default(),
+        host_configure_count: host
+            .as_ref()
+            .map(|h| h.configure_count())
+            .unwrap_or_default(),
+    })
+}
+
+fn handle_reconnect_request(daemon: &Daemon, request: &ReconnectRequest) -> Result<()> {
+    let delay = Duration::from_millis(request.delay_ms.into());
+    if delay > MAX_RECONNECT_DELAY {
+        bail!("Reconnect delay exceeds {MAX_RECONNECT_DELAY:?}: {delay:?}");
+    }
+
+    let Some(controller) = usb_controller()? else {
+        bail!("Cannot determine ID of USB controller");
+    };
+
+    let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
+    let udc = UsbController::new(&controller)?;
+
+    let (soft_connect, generation) = {
+        let mut state = daemon.gadget.lock().unwrap();
+
+        if gadget.controller()?.as_deref() != Some(&controller) {
+            bail!("Gadget is not bound to USB controller: {controller:?}");
+        }
+
+        // See configure_mass_storage().
+        let _paused_gadget_hal = PausedGadgetHal::new(daemon)?;
+
+        let soft_connect = udc.set_soft_connect(false)?;
+        if soft_connect {
+            debug!("Soft disconnected USB controller for {delay:?}");
+        } else {
+            debug!("Soft connect unsupported; unbinding USB controller for {delay:?}");
+            gadget.set_controller(None)?;
+        }
+
+        // The disconnection is caused by the daemon, so it must not count as
+        // a host reset while the lock is released below.
+        state.ignore_resets_until = Some(Instant::now() + delay + RESET_SETTLE_TIME);
+        state.reset_pending_since = None;
+        state.reconnect_generation = state.reconnect_generation.wrapping_add(1);
+
+        (soft_connect, state.reconnect_generation)
+    };
+
+    // Nothing is held while waiting so that the delay does not block other
+    // requests, the monitoring threads, or the gadget HAL.
+    thread::sleep(delay);
+
+    let mut state = daemon.gadget.lock().unwrap();
+
+    if state.reconnect_generation != generation {
+        debug!("Reconnect was superseded by another reconnect request");
+        return Ok(());
+    }
+
+    // See configure_mass_storage(). Don't leave the device disconnected if the
+    // gadget HAL cannot be paused.
+    let _paused_gadget_hal = PausedGadgetHal::new(daemon)
+        .inspect_err(|e| warn!("Reconnecting without pausing gadget HAL: {e:?}"))
+        .ok();
+
+    // Someone else may have changed the gadget while the lock was released.
+    let bound = gadget.controller()?;
+
+    if soft_connect {
+        if bound.as_deref() != Some(&controller) {
+            warn!("Gadget was unbound during reconnect; not soft connecting: {bound:?}");
+            return Ok(());
+        }
+
+        // Rebinding the gadget connects it again too.
+        if let Err(e) = udc.set_soft_connect(true) {
+            warn!("Failed to soft connect USB controller; rebinding gadget: {e:?}");
+            gadget.set_controller(None)?;
+            gadget.set_controller(Some(&controller))?;
+        }
+    } else if bound.is_none() {
+        gadget.set_controller(Some(&controller))?;
+    } else {
+        debug!("Gadget was rebound during reconnect: {bound:?}");
+    }
+
+    // See configure_mass_storage().
+    state.ignore_resets_until = Some(Instant::now() + RESET_SETTLE_TIME);
+    state.reset_pending_since = None;
+
+    Ok(())
+}
+
+fn handle_set_client_name_request(session: &mut Session, request: &SetClientNameRequest) {
+    info!("Client name: {:?}", request.name);
+
+    session.owner.name = request.name.clone();
+}
+
+fn handle_request(daemon: &Daemon, session: &mut Session, request: &Request) -> Response {
+    let ret = match request {
+        Request::GetFunctions(_) => handle_get_functions_request()
+            .map(|functions| Response::GetFunctions(GetFunctionsResponse { functions })),
+        Request::SetMassStorage(r) => handle_set_mass_storage_request(daemon, session, r)
+            .map(|()| Response::SetMassStorage(SetMassStorageResponse)),
+        Request::GetMassStorage(_) => handle_get_mass_storage_request(daemon)
+            .map(|devices| Response::GetMassStorage(GetMassStorageResponse { devices })),
+        Request::GetControllerState(_) => {
+            handle_get_controller_state_request(daemon).map(Response::GetControllerState)
+        }
+        Request::Reconnect(r) => {
+            handle_reconnect_request(daemon, r).map(|()| Response::Reconnect(ReconnectResponse))
+        }
+        Request::RenewLease(_) => handle_renew_lease_request(daemon, session)
+            .map(|()| Response::RenewLease(RenewLeaseResponse)),
+        Request::SetClientName(r) => {
+            handle_set_client_name_request(session, r);
+            Ok(Response::SetClientName(SetClientNameResponse))
+        }
+        Request::AddLun(r) => handle_add_lun_request(daemon, session, r)
+            .map(|lun| Response::AddLun(AddLunResponse { lun })),
+        Request::RemoveLun(r) => handle_remove_lun_request(daemon, session, r)
+            .map(|()| Response::RemoveLun(RemoveLunResponse)),
+        Request::ChangeMedia(r) => handle_change_media_request(daemon, session, r)
+            .map(|()| Response::ChangeMedia(ChangeMediaResponse)),
+    };
+
+    daemon.state_changed(&mut daemon.gadget.lock().unwrap());
+
+    ret.unwrap_or_else(|e| {
+        warn!("{e:?}");
+
+        Response::Error(ErrorResponse {
+            message: format!("{e:?}"),
+        })
+    })
+}
+
+fn serve_client(daemon: &Daemon, session: &mut Session, stream: &mut UnixStream) -> Result<()> {
+    loop {
+        let request = match Request::from_socket(stream) {
+            Ok(r) => r,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(e) => return Err(e).context("Failed to receive request"),
+        };
+
+        debug!("Request: {request:?}");
+
+        let response = handle_request(daemon, session, &request);
+
+        debug!("Response: {response:?}");
+
+        response
+            .to_socket(stream)
+            .with_context(|| format!("Failed to send response: {response:?}"))?;
+    }
+}
+
+fn handle_client(daemon: &Daemon, mut stream: UnixStream, ucred: UCred) -> Result<()> {
+    check_selinux()?;
+    negotiate_protocol(&mut stream)?;
+
+    let label = util::socket_peer_label(stream.as_fd())
+        .inspect_err(|e| warn!("Failed to get peer SELinux context: {e}"))
+        .ok()
+        .flatten();
+
+    debug!("Peer SELinux context: {label:?}");
+
+    let mut session = Session {
+        id: daemon.next_session_id.fetch_add(1, Ordering::Relaxed),
+        owner: LunOwner {
+            uid: ucred.uid.as_raw(),
+            label,
+            name: String::new(),
+        },
+    };
+
+    let ret = serve_client(daemon, &mut session, &mut stream);
+
+    // LUNs bound to the connection must be cleared no matter how it ended.
+    let mut state = daemon.gadget.lock().unwrap();
+    if let Err(e) = daemon.clear_luns(
+        &mut state,
+        "connection closed",
+        |r| matches!(r.lease, LunLease::Connection(id) if id == session.id),
+    ) {
+        warn!("Failed to clear LUNs bound to connection: {e:?}");
+    }
+    daemon.state_changed(&mut state);
+
+    ret
+}
+
+/// A client connection.
+#[derive(Debug)]
+struct Session {
+    id: u64,
+    /// Owner of the LUNs configured via this connection.
+    owner: LunOwner,
+}
+
+/// Condition under which a LUN is automatically cleared.
+#[derive(Debug, Clone, Copy)]
+enum LunLease {
+    None,
+    /// Cleared when the session with the specified ID ends.
+    Connection(u64),
+    /// Cleared if the lease is not renewed before the deadline. The deadline
+    /// keeps approaching while the device is suspended.
+    Timeout {
+        timeout: Duration,
+        deadline: BootInstant,
+    },
+}
+
+/// Clear a LUN once the host is done booting from it.
+#[derive(Debug, Clone, Copy)]
+struct OneShot {
+    /// Number of host resets after which the LUN is cleared. 0 if the LUN is
+    /// only cleared when the host ejects the media.
+    resets: u32,
+    /// Number of host resets observed since the LUN was configured.
+    seen: u32,
+}
+
+/// A LUN configured by the daemon.
+#[derive(Debug)]
+struct LunRecord {
+    /// Path of the backing file as reported by the kernel.
+    file: PathBuf,
+    attrs: LunAttrs,
+    state: LunState,
+    /// The daemon's reference to the backing file. This is None once the LUN
+    /// has been released.
+    fd: Option<OwnedFd>,
+    /// Size of the backing file if it is a regular file. The host cannot change
+    /// the size, so any change is external.
+    size: Option<u64>,
+    lock: Option<FileLock>,
+    /// The loop device that the kernel uses instead of the backing file. This
+    /// is detached once both the daemon and the kernel release it.
+    loop_device: Option<LoopDevice>,
+    /// When the host last read from or wrote to the LUN. This is only as
+    /// precise as the LUN poll interval.
+    last_read: Option<BootInstant>,
+    last_write: Option<BootInstant>,
+    /// The I/O statistics at the time the LUN was configured if it is backed
+    /// by a block device.
+    stats_base: Option<BlockStats>,
+    lease: LunLease,
+    /// Cleared if the host has not accessed the gadget for this long.
+    idle_timeout: Option<Duration>,
+    /// When the LUN was associated with its current file.
+    configured: BootInstant,
+    one_shot: Option<OneShot>,
+    owner: LunOwner,
+}
+
+impl LunRecord {
+    /// Get the file that the kernel actually accesses.
+    fn backing_fd(&self) -> Option<BorrowedFd<'_>> {
+        match &self.loop_device {
+            Some(l) => Some(l.as_fd()),
+            None => self.fd.as_ref().map(|fd| fd.as_fd()),
+        }
+    }
+
+    /// Get the I/O statistics since the LUN was configured.
+    fn io_stats(&self) -> Option<BlockStats> {
+        let base = self.stats_base?;
+        let fd = self.backing_fd()?;
+
+        match BlockStats::read(fd) {
+            Ok(stats) => stats.map(|s| s.since(base)),
+            Err(e) => {
+                warn!("Failed to read I/O statistics: {:?}: {e}", self.file);
+                None
+            }
+        }
+    }
+
+    /// Reacquire the lock on the backing file after it was released for a
+    /// replacement that did not happen.
+    fn relock(&mut self, lun: u8) {
+        let Some(fd) = &self.fd else {
+            return;
+        };
+
+        match FileLock::new(fd.as_fd(), !self.attrs.ro) {
+            Ok(lock) => self.lock = Some(lock),
+            Err(e) => warn!("Failed to relock file for LUN #{lun}: {e}"),
+        }
+    }
+
+    /// Get how long the host has been inactive since the LUN was configured.
+    /// This includes time that the device spent suspended, so that a LUN is
+    /// not kept alive just because the device was asleep.
+    fn idle_duration(&self, last_activity: Option<BootInstant>) -> Duration {
+        let since = match last_activity {
+            Some(t) => t.max(self.configured),
+            None => self.configured,
+        };
+
+        since.elapsed()
+    }
+}
+
+/// State of the USB gadget as configured by this daemon instance.
+#[derive(Debug, Default)]
+struct GadgetState {
+    luns: BTreeMap<u8, LunRecord>,
+    /// The gadget's device descriptors from before they were overridden.
+    original_descriptors: Option<GadgetDescriptors>,
+    /// The gadget's maximum speed from before it was limited.
+    original_max_speed: Option<String>,
+    /// The last time the host accessed a LUN or configured the gadget.
+    last_activity: Option<BootInstant>,
+    /// Host resets are ignored until this time because they were caused by
+    /// the daemon itself.
+    ignore_resets_until: Option<Instant>,
+    /// When the host stopped using the configuration if that has not been
+    /// counted as a host reset yet.
+    reset_pending_since: Option<Instant>,
+    /// Incremented every time a reconnect request disconnects the host. A
+    /// reconnect only connects the host again if no newer one has started.
+    reconnect_generation: u64,
+    /// The gadget-wide settings that the LUNs were configured with.
+    gadget_settings: PersistedGadget,
+    /// The configuration that was last saved or restored.
+    persisted_config: Option<String>,
+}
+
+/// State shared between all daemon threads.
+struct Daemon<'a> {
+    cli: &'a DaemonCli,
+    /// The mutex also serializes all operations that modify the USB gadget.
+    gadget: Mutex<GadgetState>,
+    /// USB host connection state. This is None if uevent monitoring is not
+    /// available.
+    host: Mutex<Option<HostStatus>>,
+    next_session_id: AtomicU64,
+    /// None if the daemon should not keep the device awake.
+    wake_lock: Option<WakeLock>,
+    /// None if the configuration should not be persisted.
+    config_file: Option<ConfigFile>,
+    /// None if the watchdog could not be spawned.
+    watchdog: Option<Watchdog>,
+}
+
+impl Daemon<'_> {
+    /// Detect LUNs with removable media that were ejected by the host. The
+    /// kernel clears the LUN's file when this happens.
+    fn check_ejected_luns(&self, state: &mut GadgetState) -> Result<()> {
+        if state.luns.values().all(|r| r.state != LunState::Active) {
+            return Ok(());
+        }
+
+        let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
+        let function_name = detect_function_name(&gadget)?;
+        let Some(function) = gadget.open_mass_storage_function(&function_name)? else {
+            return Ok(());
+        };
+
+        for (lun, record) in &mut state.luns {
+            if record.state != LunState::Active {
+                continue;
+            }
+
+            let (file, _) = function.get_lun(*lun)?;
+            if file.is_some() {
+                continue;
+            }
+
+            info!("LUN #{lun} was ejected by the host: {:?}", record.file);
+            record.state = LunState::EjectedByHost;
+
+            // Removing the LUN itself would unbind the gadget, so the LUN is
+            // only deleted during the next reconfiguration.
+            if self.cli.release_ejected {
+                debug!("Releasing fd for LUN #{lun}");
+                record.fd = None;
+                record.lock = None;
+                record.loop_device = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the session is allowed to modify the specified LUNs. LUNs
+    /// owned by another client can only be modified if ownership is not
+    /// enforced or if `force` is set.
+    fn check_lun_owner(
+        &self,
+        state: &GadgetState,
+        luns: &[u8],
+        session: &Session,
+        force: bool,
+    ) -> Result<()> {
+        for lun in luns {
+            let Some(record) = state.luns.get(lun) else {
+                continue;
+            };
+
+            if record.owner == session.owner {
+                continue;
+            }
+
+            if self.cli.enforce_ownership && !force {
+                bail!("LUN #{lun} is owned by another client: {:?}", record.owner);
+            }
+
+            warn!(
+                "Modifying LUN #{lun} owned by another client: {:?}",
+                record.owner
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Get the idle timeout for newly configured LUNs, falling back to the
+    /// daemon's default if the client did not specify one.
+    fn idle_timeout(&self, timeout_ms: u32) -> Option<Duration> {
+        Some(timeout_ms)
+            .filter(|t| *t != 0)
+            .or(self.cli.idle_timeout_ms)
+            .map(|t| Duration::from_millis(t.into()))
+    }
+
+    /// Get the one-shot settings for newly configured LUNs.
+    fn one_shot(&self, resets: Option<u32>) -> Result<Option<OneShot>> {
+        let Some(resets) = resets else {
+            return Ok(None);
+        };
+
+        if resets > 0 && self.host.lock().unwrap().is_none() {
+            bail!("Cannot detect host resets because the USB host state is not tracked");
+        }
+
+        Ok(Some(OneShot { resets, seen: 0 }))
+    }
+
+    /// Record that the host stopped using the configuration. This only counts
+    /// as a host reset once the host stays unconfigured for long enough.
+    fn start_host_reset(&self, state: &mut GadgetState) {
+        if state
+            .ignore_resets_until
+            .is_some_and(|t| Instant::now() < t)
+        {
+            debug!("Ignoring host reset caused by the daemon");
+        } else if state.reset_pending_since.is_none() {
+            state.reset_pending_since = Some(Instant::now());
+        }
+    }
+
+    /// Count the pending host reset if the host stayed unconfigured for long
+    /// enough. If the host already configured the device again, a reset that
+    /// was too short is discarded because it was likely part of the same boot.
+    fn check_host_reset(&self, state: &mut GadgetState, configured: bool) -> Result<()> {
+        let Some(since) = state.reset_pending_since else {
+            return Ok(());
+        };
+
+        if since.elapsed() < RESET_DEBOUNCE_TIME {
+            if configured {
+                debug!("Ignoring brief host reset");
+                state.reset_pending_since = None;
+            }
+
+            return Ok(());
+        }
+
+        state.reset_pending_since = None;
+        self.handle_host_reset(state)
+    }
+
+    /// Count a host reset towards the limit of one-shot LUNs and remove the
+    /// LUNs that reached it.
+    fn handle_host_reset(&self, state: &mut GadgetState) -> Result<()> {
+        for (lun, record) in &mut state.luns {
+            if let Some(one_shot) = &mut record.one_shot {
+                one_shot.seen = one_shot.seen.saturating_add(1);
+                debug!(
+                    "LUN #{lun} observed host reset {} of {}",
+                    one_shot.seen, one_shot.resets,
+                );
+            }
+        }
+
+        self.clear_luns(state, "host reset limit reached", |r| {
+            r.one_shot
+                .is_some_and(|o| o.resets > 0 && o.seen >= o.resets)
+        })
+    }
+
+    /// Remove all LUNs whose records match the predicate.
+    fn clear_luns(
+        &self,
+        state: &mut GadgetState,
+        reason: &str,
+        predicate: impl Fn(&LunRecord) -> bool,
+    ) -> Result<()> {
+        let luns = state
+            .luns
+            .iter()
+            .filter(|(_, r)| predicate(r))
+            .map(|(lun, _)| *lun)
+            .collect::<Vec<_>>();
+        if luns.is_empty() {
+            return Ok(());
+        }
+
+        info!("Removing LUNs {luns:?}: {reason}");
+
+        remove_luns(self, state, &luns)
+    }
+
+    /// Hold the wake lock while at least one LUN is active and a host is
+    /// connected. If the host state is not tracked, the host is assumed to be
+    /// connected.
+    fn update_wake_lock(&self, state: &GadgetState) {
+        let Some(wake_lock) = &self.wake_lock else {
+            return;
+        };
+
+        let host_connected = self
+            .host
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|h| matches!(h.state(), HostState::Connected | HostState::Configured));
+        let lun_active = state.luns.values().any(|r| r.state == LunState::Active);
+        let held = host_connected && lun_active;
+
+        match wake_lock.set_held(held) {
+            Ok(true) if held => info!("Acquired wake lock: {}", wake_lock.name()),
+            Ok(true) => info!("Released wake lock: {}", wake_lock.name()),
+            Ok(false) => {}
+            Err(e) => warn!("Failed to update wake lock: {e:?}"),
+        }
+    }
+
+    /// Get the serialized form of the LUNs that should be restored after a
+    /// reboot. Temporary LUNs, like those with a lease, are excluded.
+    fn persisted_config(&self, state: &GadgetState) -> String {
+        let mut luns = vec![];
+
+        for (lun, record) in &state.luns {
+            // The kernel only knows the path of the loop device, which is
+            // meaningless after a reboot.
+            if record.state != LunState::Active
+                || !matches!(record.lease, LunLease::None)
+                || record.one_shot.is_some()
+                || record.loop_device.is_some()
+            {
+                continue;
+            }
+            let Some(fd) = &record.fd else {
+                continue;
+            };
+
+            match util::file_id(fd.as_fd()) {
+                Ok((dev, ino)) => luns.push(PersistedLun {
+                    lun: *lun,
+                    file: record.file.clone(),
+                    dev,
+                    ino,
+                    attrs: record.attrs.clone(),
+                }),
+                Err(e) => warn!("Failed to identify file for LUN #{lun}: {e}"),
+            }
+        }
+
+        persist::serialize(&PersistedConfig {
+            gadget: state.gadget_settings.clone(),
+            luns,
+        })
+    }
+
+    /// Save the configuration if it changed since it was last saved.
+    fn save_config(&self, state: &mut GadgetState) {
+        let Some(config_file) = &self.config_file else {
+            return;
+        };
+
+        let data = self.persisted_config(state);
+        if state.persisted_config.as_ref() == Some(&data) {
+            return;
+        }
+
+        match config_file.save(&data) {
+            Ok(()) => {
+                debug!("Saved configuration: {:?}", config_file.path());
+                state.persisted_config = Some(data);
+            }
+            Err(e) => warn!("Failed to save configuration: {e:?}"),
+        }
+    }
+
+    /// Apply the side effects of the gadget state potentially changing.
+    fn state_changed(&self, state: &mut GadgetState) {
+        self.update_wake_lock(state);
+        self.save_config(state);
+    }
+
+    /// If FUSE bypass is enabled and the device's file is on Android's FUSE
+    /// filesystem, get an equivalent device that refers to the lower file
+    /// instead. Failures are not fatal because the FUSE file still works, just
+    /// more slowly.
+    fn bypass_fuse(&self, device: &MassStorageDevice) -> Option<MassStorageDevice> {
+        if !self.cli.bypass_fuse {
+            return None;
+        }
+
+        match open_fuse_lower_file(device) {
+            Ok(fd) => fd.map(|fd| MassStorageDevice {
+                fd,
+                cdrom: device.cdrom,
+                ro: device.ro,
+                removable: device.removable,
+                nofua: device.nofua,
+                inquiry_string: device.inquiry_string.clone(),
+            }),
+            Err(e) => {
+                warn!("Failed to bypass FUSE: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// Acquire the resources for the devices. The locks of the `replaced` LUNs
+    /// are released first so that they do not conflict with their
+    /// replacements. They are reacquired if any device cannot be prepared.
+    fn prepare_devices(
+        &self,
+        state: &mut GadgetState,
+        devices: &[&MassStorageDevice],
+        replaced: &[u8],
+    ) -> Result<Vec<DeviceResources>> {
+        let mut unlocked = vec![];
+        for lun in replaced {
+            if let Some(record) = state.luns.get_mut(lun)
+                && record.lock.take().is_some()
+            {
+                unlocked.push(*lun);
+            }
+        }
+
+        let ret = devices
+            .iter()
+            .map(|d| {
+                Ok(DeviceResources {
+                    lock: lock_device(d)?,
+                    loop_device: self.loop_device(d)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>();
+
+        if ret.is_err() {
+            for lun in unlocked {
+                state.luns.get_mut(&lun).unwrap().relock(lun);
+            }
+        }
+
+        ret
+    }
+
+    /// Detect LUNs whose files were modified by something other than the host
+    /// and eject them if enabled. For read-write LUNs, only changes to the file
+    /// size are detected because the host's writes are indistinguishable from
+    /// other writes.
+    fn check_modified_luns(
+        &self,
+        state: &mut GadgetState,
+        activity: &BTreeMap<u8, IoActivity>,
+    ) -> Result<()> {
+        let mut modified = vec![];
+
+        for (lun, record) in &mut state.luns {
+            if record.state != LunState::Active {
+                continue;
+            }
+            let Some(fd) = &record.fd else {
+                continue;
+            };
+
+            if let Some(size) = record.size {
+                let new_size = match rustix::fs::fstat(fd) {
+                    Ok(stat) => stat.st_size as u64,
+                    Err(e) => {
+                        warn!("Failed to stat file for LUN #{lun}: {e}");
+                        continue;
+                    }
+                };
+
+                if new_size != size {
+                    warn!(
+                        "LUN #{lun} was resized externally from {size} to {new_size} bytes: {:?}",
+                        record.file,
+                    );
+                    record.size = Some(new_size);
+                    modified.push(*lun);
+                    continue;
+                }
+            }
+
+            if record.attrs.ro && activity.get(lun).is_some_and(|a| a.written) {
+                warn!("LUN #{lun} was written to externally: {:?}", record.file);
+                modified.push(*lun);
+            }
+        }
+
+        if !self.cli.eject_on_change || modified.is_empty() {
+            return Ok(());
+        }
+
+        let gadget = UsbGadget::new(GADGET_ROOT, CONFIGS_NAME)?;
+        let function_name = detect_function_name(&gadget)?;
+        let Some(function) = gadget.open_mass_storage_function(&function_name)? else {
+            return Ok(());
+        };
+
+        for lun in modified {
+            info!("Ejecting LUN #{lun} because its file was modified externally");
+            function.forced_eject(lun)?;
+            state.luns.get_mut(&lun).unwrap().state = LunState::EjectedOnChange;
+        }
+
+        Ok(())
+    }
+
+    /// Attach the device to a loop device if needed, depending on the loop
+    /// device mode.
+    fn loop_device(&self, device: &MassStorageDevice) -> Result<Option<LoopDevice>> {
+        let needed = match self.cli.loop_device {
+            LoopMode::Never => false,
+            LoopMode::Fallback => !can_reopen(device),
+            LoopMode::Always => true,
+        };
+        if !needed {
+            return Ok(None);
+        }
+
+        LoopDevice::attach(device.fd.as_fd(), device.ro)
+            .map(Some)
+            .with_context(|| format!("Failed to attach loop device: {:?}", device.fd))
+    }
+
+    /// Update the host state. Returns the previous state if the state changed.
+    fn set_host_state(&self, state: HostState) -> Option<HostState> {
+        let mut host = self.host.lock().unwrap();
+        let host = host.as_mut()?;
+
+        let old_state = host.transition(state)?;
+        info!(
+            "USB host state changed: {old_state:?} -> {state:?} (connects: {}, configures: {})",
+            host.connect_count(),
+            host.configure_count(),
+        );
+
+        Some(old_state)
+    }
+}
+
+/// Make sure the I/O monitor is watching the backing files of all LUNs that the
+/// daemon holds a reference to.
+fn update_io_monitor(monitor: &mut Option<IoMonitor>, state: &GadgetState) -> Result<()> {
+    let luns = state
+        .luns
+        .iter()
+        // The loop driver bypasses the VFS when accessing the backing file, so
+        // only the loop device itself produces events.
+        .filter_map(|(lun, r)| r.backing_fd().map(|fd| (*lun, fd)))
+        .collect::<Vec<_>>();
+
+    if let Some(m) = monitor
+        && m.is_watching(&luns)?
+    {
+        return Ok(());
+    }
+
+    *monitor = None;
+
+    if !luns.is_empty() {
+        debug!(
+            "Monitoring host I/O on LUNs: {:?}",
+            luns.iter().map(|(lun, _)| lun)
+        );
+        *monitor = Some(IoMonitor::new(&luns).context("Failed to set up I/O monitor")?);
+    }
+
+    Ok(())
+}
+
+/// Open a saved LUN's backing file. The file must be inside the restore
+/// directory and must be the same file that was originally configured.
+fn open_persisted_lun(dir: &Dir, dir_path: &Path, lun: &PersistedLun) -> Result<MassStorageDevice> {
+    let path = lun
+        .file
+        .strip_prefix(dir_path)
+        .map_err(|_| anyhow!("File is outside of restore directory: {:?}", lun.file))?;
+
+    let file = dir
+        .open_with(path, OpenOptions::new().read(true).write(!lun.attrs.ro))
+        .with_context(|| format!("Failed to open file: {:?}", lun.file))?;
+    let fd = OwnedFd::from(file.into_std());
+
+    if util::file_id(fd.as_fd())? != (lun.dev, lun.ino) {
+        bail!("File was replaced since it was configured: {:?}", lun.file);
+    }
+
+    Ok(MassStorageDevice {
+        fd,
+        cdrom: lun.attrs.cdrom,
+        ro: lun.attrs.ro,
+        removable: lun.attrs.removable,
+        nofua: lun.attrs.nofua,
+        inquiry_string: lun.attrs.inquiry_string.clone(),
+    })
+}
+
+/// Reapply the configuration that was saved before the daemon last exited.
+/// LUNs that cannot be restored are skipped. The saved configuration is only
+/// overwritten once the configuration changes again.
+fn restore_config(daemon: &Daemon) -> Result<()> {
+    let (Some(config_file), Some(restore_dir)) = (&daemon.config_file, &daemon.cli.restore_dir)
+    else {
+        return Ok(());
+    };
+
+    let mut state = daemon.gadget.lock().unwrap();
+    state.persisted_config = Some(daemon.persisted_config(&state));
+
+    let Some(config) = config_file.load()? else {
+        debug!("No saved configuration: {:?}", config_file.path());
+        return Ok(());
+    };
+
+    let restore_dir = fs::canonicalize(restore_dir)
+        .with_context(|| format!("Failed to canonicalize path: {restore_dir:?}"))?;
+    let dir = Dir::open_ambient_dir(&restore_dir, ambient_authority())
+        .with_context(|| format!("Failed to open directory: {restore_dir:?}"))?;
+    let mut devices = vec![];
+
+    for lun in &config.luns {
+        match open_persisted_lun(&dir, &restore_dir, lun) {
+            Ok(device) => devices.push(device),
+            Err(e) => warn!("Not restoring LUN #{}: {e:?}", lun.lun),
+        }
+    }
+
+    if devices.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "Restoring {} of {} saved LUNs",
+        devices.len(),
+        config.luns.len(),
+    );
+
+    let request = SetMassStorageRequest {
+        devices,
+        lease: Lease::None,
+        force: false,
+        stall: config.gadget.stall,
+        descriptors: config.gadget.descriptors,
+        max_speed: config.gadget.max_speed,
+        idle_timeout_ms: 0,
+        one_shot: None,
+    };
+    let owner = LunOwner {
+        uid: rustix::process::getuid().as_raw(),
+        label: None,
+        name: RESTORE_OWNER_NAME.to_owned(),
+    };
+
+    configure_mass_storage(daemon, &mut state, &request, LunLease::None, &owner)?;
+
+    state.persisted_config = Some(daemon.persisted_config(&state));
+
+    Ok(())
+}
+
+/// Attach the default devices specified on the command line. Nothing is
+/// attached if any of the files cannot be opened.
+fn attach_default_devices(daemon: &Daemon, state: &mut GadgetState) -> Result<()> {
+    let cli = daemon.cli;
+    let devices = cli
+        .default_file
+        .iter()
+        .zip(&cli.default_type)
+        .map(|(path, type_)| {
+            let file = File::options()
+                .read(true)
+                .write(*type_ == MassStorageType::DiskRw)
+                .open(path)
+                .with_context(|| format!("Failed to open file: {path:?}"))?;
+
+            Ok(MassStorageDevice {
+                fd: file.into(),
+                cdrom: *type_ == MassStorageType::Cdrom,
+                ro: *type_ != MassStorageType::DiskRw,
+                removable: true,
+                nofua: false,
+                inquiry_string: String::new(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    info!("Attaching {} default devices", devices.len());
+
+    let request = SetMassStorageRequest {
+        devices,
+        lease: Lease::None,
+        force: false,
+        stall: true,
+        descriptors: DescriptorOverrides::default(),
+        max_speed: None,
+        idle_timeout_ms: 0,
+        one_shot: None,
+    };
+    let owner = LunOwner {
+        uid: rustix::process::getuid().as_raw(),
+        label: None,
+        name: DEFAULT_OWNER_NAME.to_owned(),
+    };
+
+    configure_mass_storage(daemon, state, &request, LunLease::None, &owner)
+}
+
+/// Query the USB controller state via `watcher`. The watcher is reopened if the
+/// controller was removed and added back. If querying fails, the watcher is
+/// closed so that a stale file is not polled.
+fn query_host_state(controller: &str, watcher: &mut Option<UsbStateWatcher>) -> Result<HostState> {
+    if let Some(w) = watcher {
+        match w.read() {
+            Ok(state) => return Ok(HostState::from_udc_state(&state)),
+            Err(e) => {
+                debug!("Reopening USB controller state: {e:?}");
+                *watcher = None;
+            }
+        }
+    }
+
+    let w = UsbController::new(controller)?.watch_state()?;
+    let state = w.read()?;
+    *watcher = Some(w);
+
+    Ok(HostState::from_udc_state(&state))
+}
+
+/// Sources of USB host connection state changes.
+struct HostMonitor {
+    socket: UeventSocket,
+    controller: String,
+    /// None if the USB controller's `state` attribute cannot be watched.
+    watcher: Option<UsbStateWatcher>,
+}
+
+/// Open the uevent socket and initialize the host state machine with the
+/// current USB controller state.
+fn init_uevent_monitor(daemon: &Daemon) -> Result<HostMonitor> {
+    let Some(controller) = usb_controller()? else {
+        bail!("Cannot determine ID of USB controller");
+    };
+
+    // Open the socket first so that no events are missed between querying the
+    // initial state and listening for changes.
+    let socket = UeventSocket::new().context("Failed to open uevent socket")?;
+    let mut watcher = None;
+    let state = query_host_state(&controller, &mut watcher)?;
+
+    debug!("Initial USB host state: {state:?}");
+    *daemon.host.lock().unwrap() = Some(HostStatus::new(state));
+
+    Ok(HostMonitor {
+        socket,
+        controller,
+        watcher,
+    })
+}
+
+/// Track the USB host connection state via `udc` and `android_usb` uevents and
+/// the USB controller's `state` attribute. The latter is the only way to detect
+/// that the host suspended the device because that does not cause a uevent.
+fn monitor_uevents(daemon: &Daemon, monitor: &mut HostMonitor) -> Result<()> {
+    loop {
+        let (uevent_ready, state_changed) = {
+            let mut fds = vec![PollFd::new(&monitor.socket, PollFlags::IN)];
+            if let Some(w) = &monitor.watcher {
+                fds.push(PollFd::new(w, PollFlags::PRI));
+            }
+
+            match rustix::event::poll(&mut fds, None) {
+                Ok(_) => {}
+                Err(Errno::INTR) => continue,
+                Err(e) => return Err(e).context("Failed to poll for USB host events"),
+            }
+
+            (
+                !fds[0].revents().is_empty(),
+                fds.get(1).is_some_and(|f| !f.revents().is_empty()),
+            )
+        };
+
+        let mut events = vec![];
+
+        if uevent_ready {
+            loop {
+                match monitor.socket.try_recv() {
+                    Ok(Some(uevent)) => {
+                        let Some(event) = HostEvent::from_uevent(&uevent, &monitor.controller)
+                        else {
+                            continue;
+                        };
+
+                        debug!("Host event: {event:?}: {uevent:?}");
+                        events.push(event);
+                    }
+                    Ok(None) => break,
+                    // The missed uevents may have included state changes, so
+                    // the state must be queried again.
+                    Err(e) if e.raw_os_error() == Some(Errno::NOBUFS.raw_os_error()) => {
+                        warn!("Uevents were dropped; querying USB controller state");
+                        events.push(HostEvent::ControllerChanged);
+                    }
+                    Err(e) => return Err(e).context("Failed to receive uevent"),
+                }
+            }
+        }
+
+        if state_changed {
+            debug!("USB controller state attribute changed");
+            events.push(HostEvent::ControllerChanged);
+        }
+
+        for event in events {
+            let state = match event {
+                HostEvent::State(state) => state,
+                HostEvent::ControllerChanged => {
+                    match query_host_state(&monitor.controller, &mut monitor.watcher) {
+                        Ok(state) => state,
+                        Err(e) => {
+                            warn!("Failed to query USB controller state: {e:?}");
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            handle_host_state(daemon, state);
+        }
+    }
+}
+
+/// Update the host state machine and react to the host's state change.
+fn handle_host_state(daemon: &Daemon, state: HostState) {
+    let old_state = daemon.set_host_state(state);
+
+    let mut gadget = daemon.gadget.lock().unwrap();
+    if state == HostState::Configured {
+        gadget.last_activity = Some(BootInstant::now());
+
+        if old_state.is_some()
+            && let Err(e) = daemon.check_host_reset(&mut gadget, true)
+        {
+            warn!("Failed to handle host reset: {e:?}");
+        }
+    }
+
+    // The host stopped using the configuration, either due to a bus reset
+    // or disconnecting.
+    if matches!(
+        old_state,
+        Some(HostState::Configured | HostState::Suspended),
+    ) && matches!(state, HostState::Connected | HostState::Disconnected)
+    {
+        daemon.start_host_reset(&mut gadget);
+    }
+
+    // The cable was plugged in. Rebinding the gadget also makes the host
+    // reconnect, so changes made by the daemon itself are ignored. This
+    // prevents the default devices from coming back after they are
+    // cleared.
+    if old_state == Some(HostState::Disconnected)
+        && matches!(state, HostState::Connected | HostState::Configured)
+        && !daemon.cli.default_file.is_empty()
+        && gadget.luns.is_empty()
+        && gadget
+            .ignore_resets_until
+            .is_none_or(|t| Instant::now() >= t)
+        && let Err(e) = attach_default_devices(daemon, &mut gadget)
+    {
+        warn!("Failed to attach default devices: {e:?}");
+    }
+
+    daemon.state_changed(&mut gadget);
+}
+
+fn drop_privileges(block_suspend: bool) -> Result<()> {
+    // The only thing we need root level permissions for is chown'ing newly
+    // created files on configfs. Unlike other filesystems, newly created files
+    // on configfs are always owned by root:root. There was a patch from 2021 to
+    // fix this behavior, but it was never accepted.
+    // https://lore.kernel.org/lkml/20210123205516.2738060-1-zenczykowski@gmail.com/
+    //
+    // For ADB/MTP/etc., AOSP works around this by having an init script create
+    // the paths on configfs and chown them appropriately. This approach does
+    // not work for us because creating a LUN that's not associated with a file
+    // still results in a 0-sized device being advertised. This prevents some
+    // machines from booting from another mass storage device. Bootable devices
+    // is an important use case for MSD, so we're stuck with requiring elevated
+    // privileges.
+    //
+    // There are 2 ways the daemon can be run. If we're running runing as
+    // system:system, then the parent process is responsible for execve'ing with
+    // CAP_CHROOT allowed. If we're running as root:root, then we drop all
+    // capabilities besides CAP_CHROOT and drop privileges to system:system.
+    //
+    // If the daemon manages a wake lock, then CAP_BLOCK_SUSPEND is kept too.
+    // It is also made inheritable so that it can be passed on to the watchdog.
+    // CAP_LEASE is kept if possible so that the daemon can check whether files
+    // owned by other users are still being written to.
+
+    let system_uid = Uid::from_raw(1000);
+    let system_gid = Gid::from_raw(1000);
+    let real_uid = rustix::process::getuid();
+    let real_gid = rustix::process::getgid();
+
+    let supplementary_groups = &[
+        // Android 10 emulator with sdcardfs.
+        Gid::from_raw(1015), // sdcard_rw
+        // Samsung with sdcardfs and LineageOS GSI with fuse-bpf.
+        Gid::from_raw(1023), // media_rw
+        Gid::from_raw(9997), // everybody
+        // Access to /sys/power/wake_lock.
+        Gid::from_raw(3010), // wakelock
+    ];
+
+    let mut capabilities = CapabilitySet::CHOWN | CapabilitySet::LEASE;
+    let mut inheritable = CapabilitySet::empty();
+    if block_suspend {
+        capabilities |= CapabilitySet::BLOCK_SUSPEND;
+        inheritable |= CapabilitySet::BLOCK_SUSPEND;
+    }
+
+    if real_uid == system_uid && real_gid == system_gid {
+        let capability_set =
+            rustix::thread::capabilities(None).context("Failed to query capabilities")?;
+
+        if !capability_set.effective.contains(CapabilitySet::CHOWN) {
+            bail!("CAP_CHOWN is required when running as system user");
+        }
+        if block_suspend
+            && !capability_set
+                .effective
+                .contains(CapabilitySet::BLOCK_SUSPEND)
+        {
+            bail!("CAP_BLOCK_SUSPEND is required for the wake lock when running as system user");
+        }
+        if !capability_set.effective.contains(CapabilitySet::LEASE) {
+            warn!("CAP_LEASE is missing; files being written to may not be detected");
+            capabilities.remove(CapabilitySet::LEASE);
+        }
+    } else if real_uid == Uid::ROOT && real_gid == Gid::ROOT {
+        rustix::thread::set_keep_capabilities(true)
+            .context("Failed to set keep capabilities flag")?;
+
+        debug!("uid={system_uid:?}, gid={system_gid:?}, groups={supplementary_groups:?}");
+
+        rustix::thread::set_thread_groups(supplementary_groups)
+            .context("Failed to set supplementary groups")?;
+        rustix::thread::set_thread_res_gid(system_gid, system_gid, system_gid)
+            .context("Failed to switch GID to system group")?;
+        rustix::thread::set_thread_res_uid(system_uid, system_uid, system_uid)
+            .context("Failed to switch UID to system user")?;
+    } else {
+        bail!("Must run as root or system user, not {real_uid:?} {real_gid:?}");
+    }
+
+    let capability_set = CapabilitySets {
+        effective: capabilities,
+        permitted: capabilities,
+        inheritable,
+    };
+
+    rustix::thread::set_capabilities(None, capability_set)
+        .context("Failed to drop capabilities")?;
+
+    Ok(())
+}
+
+pub fn subcommand_daemon(cli: &DaemonCli) -> Result<()> {
+    if cli.watchdog {
+        let _span = info_span!("watchdog").entered();
+        return run_watchdog(cli);
+    }
+
+    if cli.default_file.len() != cli.default_type.len() {
+        bail!("--default-file and --default-type must be specified the same number of times");
+    }
+
+    // This must be opened before dropping privileges.
+    let config_file = cli
+        .persist_config
+        .as_deref()
+        .map(ConfigFile::open)
+        .transpose()
+        .context("Failed to open configuration directory")?;
+
+    drop_privileges(cli.wake_lock.is_some())?;
+
+    // This must happen before the gadget HAL is touched. The watchdog of a
+    // previous instance of the daemon holds the socket while it cleans up.
+    let listener = bind_socket(DAEMON_BIND_TIMEOUT)?
+        .ok_or_else(|| anyhow!("Domain socket is in use by another process"))?;
+
+    // A previous instance of the daemon might have died while the gadget HAL
+    // was paused.
+    if let Err(e) = resume_gadget_hal(&cli.gadget_hal_domain) {
+        warn!("Failed to resume gadget HAL: {e:?}");
+    }
+
+    let watchdog = Watchdog::spawn(cli.wake_lock.is_some())
+        .inspect_err(|e| warn!("Gadget HAL will not be resumed if daemon crashes: {e:?}"))
+        .ok();
+
+    let wake_lock = cli
+        .wake_lock
+        .as_deref()
+        .map(WakeLock::new)
+        .transpose()
+        .context("Failed to initialize wake lock")?;
+
+    // A previous instance of the daemon might have died while holding the
+    // wake lock.
+    if let Some(wake_lock) = &wake_lock {
+        match wake_lock.release_stale() {
+            Ok(true) => info!("Released stale wake lock: {}", wake_lock.name()),
+            Ok(false) => {}
+            Err(e) => warn!("Failed to release stale wake lock: {e:?}"),
+        }
+    }
+
+    let daemon = &Daemon {
+        cli,
+        gadget: Mutex::new(GadgetState::default()),
+        host: Mutex::new(None),
+        next_session_id: AtomicU64::new(0),
+        wake_lock,
+        config_file,
+        watchdog,
+    };
+
+    if let Err(e) = restore_config(daemon) {
+        warn!("Failed to restore saved configuration: {e:?}");
+    }
+
+    thread::scope(|scope| -> Result<()> {
+        match init_uevent_monitor(daemon) {
+            Ok(mut monitor) => {
+                scope.spawn(move || {
+                    let _span = info_span!("uevent").entered();
+
+                    if let Err(e) = monitor_uevents(daemon, &mut monitor) {
+                        error!("Thread failed: {e:?}");
+                    }
+
+                    *daemon.host.lock().unwrap() = None;
+                });
+            }
+            Err(e) => warn!("USB host state will not be tracked: {e:?}"),
+        }
+
+        scope.spawn(|| {
+            let _span = info_span!("lun").entered();
+            let mut io_monitor = None;
+
+            loop {
+                thread::sleep(LUN_POLL_INTERVAL);
+
+                let mut state = daemon.gadget.lock().unwrap();
+                if let Err(e) = daemon.check_ejected_luns(&mut state) {
+                    warn!("Failed to check for ejected LUNs: {e:?}");
+                }
+
+                if let Err(e) = daemon.check_host_reset(&mut state, false) {
+                    warn!("Failed to handle host reset: {e:?}");
+                }
+
+                if let Err(e) = daemon.clear_luns(&mut state, "ejected in one-shot mode", |r| {
+                    r.one_shot.is_some() && r.state == LunState::EjectedByHost
+                }) {
+                    warn!("Failed to clear ejected one-shot LUNs: {e:?}");
+                }
+
+                let now = BootInstant::now();
+                if let Err(e) = daemon.clear_luns(
+                    &mut state,
+                    "lease expired",
+                    |r| matches!(r.lease, LunLease::Timeout { deadline, .. } if deadline <= now),
+                ) {
+                    warn!("Failed to clear LUNs with expired lease: {e:?}");
+                }
+
+                if let Err(e) = update_io_monitor(&mut io_monitor, &state) {
+                    warn!("Failed to monitor host I/O: {e:?}");
+                    io_monitor = None;
+                }
+                let activity = match io_monitor.as_ref().map(|m| m.read_events()) {
+                    Some(Ok(a)) => a,
+                    Some(Err(e)) => {
+                        warn!("Failed to read I/O events: {e:?}");
+                        BTreeMap::new()
+                    }
+                    None => BTreeMap::new(),
+                };
+                if !activity.is_empty() {
+                    debug!("Host I/O on LUNs {activity:?}");
+                    state.last_activity = Some(now);
+                }
+                for (lun, a) in &activity {
+                    let Some(record) = state.luns.get_mut(lun) else {
+                        continue;
+                    };
+
+                    if a.read {
+                        record.last_read = Some(now);
+                    }
+                    // The host cannot write to read-only LUNs.
+                    if a.written && !record.attrs.ro {
+                        record.last_write = Some(now);
+                    }
+                }
+
+                if let Err(e) = daemon.check_modified_luns(&mut state, &activity) {
+                    warn!("Failed to check for modified LUNs: {e:?}");
+                }
+
+                let last_activity = state.last_activity;
+                if let Err(e) = daemon.clear_luns(&mut state, "host inactive", |r| {
+                    r.idle_timeout
+                        .is_some_and(|t| r.idle_duration(last_activity) >= t)
+                }) {
+                    warn!("Failed to clear inactive LUNs: {e:?}");
+                }
+
+                daemon.state_changed(&mut state);
+            }
+        });
+
+        for stream in listener.incoming() {
+            let stream = stream.context("Failed to accept incoming connection")?;
+            let ucred = rustix::net::sockopt::socket_peercred(&stream)
+                .context("Failed to get socket peer credentials")?;
+
+            scope.spawn(move || {
+                let _span = info_span!(
+                    "peer",
+                    pid = ucred.pid.as_raw_nonzero(),
+                    uid = ucred.uid.as_raw(),
+                    gid = ucred.gid.as_raw(),
+                )
+                .entered();
+
+                if ucred.pid == rustix::process::getpid() {
+                    error!("SELinux rules are broken; able to connect to self");
+                    return;
+                }
+
+                info!("Received connection");
+
+                if let Err(e) = handle_client(daemon, stream, ucred) {
+                    error!("Thread failed: {e:?}");
+                }
+            });
+        }
+
+        unreachable!()
+    })?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StopMethod {
+    /// Freeze the gadget HAL's cgroup, falling back to SIGSTOP if the cgroup v2
+    /// freezer is unavailable.
+    Freezer,
+    /// Send SIGSTOP to the gadget HAL.
+    Signal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LoopMode {
+    /// Always let the kernel reopen the file.
+    Never,
+    /// Use a loop device if the file cannot be reopened.
+    Fallback,
+    /// Always use a loop device.
+    Always,
+}
+
+/// Run daemon.
+#[derive(Debug, Parser)]
+pub struct DaemonCli {
+    /// How to pause the gadget HAL while reconfiguring the USB controller.
+    #[arg(long, value_name = "METHOD", default_value = "freezer")]
+    stop_method: StopMethod,
+
+    /// SELinux domain of the gadget HAL.
+    ///
+    /// This can be specified multiple times. Processes with the
+    /// android.hardware.usb.gadget-service* executable name are always treated
+    /// as the gadget HAL. The SELinux policy from sepatch only allows the
+    /// daemon to find and pause processes in the default domains, so custom
+    /// domains require a matching policy to be loaded separately.
+    #[arg(long, value_name = "DOMAIN", default_values = GADGET_HAL_DOMAINS)]
+    gadget_hal_domain: Vec<String>,
+
+    /// Close the daemon's reference to an image once the host ejects it.
+    #[arg(long)]
+    release_ejected: bool,
+
+    /// Eject the media if its file is modified by something other than the
+    /// host.
+    ///
+    /// Modifications are always logged. For read-write LUNs, only changes to
+    /// the file size are detected.
+    #[arg(long)]
+    eject_on_change: bool,
+
+    /// Only allow clients to replace LUNs that they own.
+    ///
+    /// A client is identified by its UID, SELinux context, and the name it
+    /// supplies. Clients can still replace other clients' LUNs by explicitly
+    /// forcing the operation.
+    #[arg(long)]
+    enforce_ownership: bool,
+
+    /// Keep the device awake while mass storage is active and a host is
+    /// connected.
+    ///
+    /// The wake lock is acquired via /sys/power/wake_lock with the specified
+    /// name.
+    #[arg(
+        long,
+        value_name = "NAME",
+        num_args = 0..=1,
+        default_missing_value = WAKE_LOCK_NAME,
+    )]
+    wake_lock: Option<String>,
+
+    /// Clear LUNs after the host has not accessed them for this many
+    /// milliseconds.
+    ///
+    /// The timer restarts whenever the host reads or writes a LUN or
+    /// configures the device. Clients can specify their own timeout.
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u32).range(1..))]
+    idle_timeout_ms: Option<u32>,
+
+    /// Save the mass storage configuration to this file whenever it changes.
+    ///
+    /// The saved configuration is restored when the daemon starts. Devices
+    /// that are cleared automatically, like those with a lease or in one-shot
+    /// mode, are not saved. The SELinux policy from sepatch only allows the
+    /// daemon to write to the default location's directory.
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = persist::DEFAULT_PATH,
+        requires = "restore_dir",
+    )]
+    persist_config: Option<PathBuf>,
+
+    /// Directory that saved devices must be in to be restored.
+    ///
+    /// Files that were replaced since they were configured are not restored.
+    #[arg(long, value_name = "DIR", requires = "persist_config")]
+    restore_dir: Option<PathBuf>,
+
+    /// Allow block devices to be used as the backing files of LUNs.
+    ///
+    /// Block devices that are mounted or in use by another driver are always
+    /// rejected.
+    #[arg(long)]
+    allow_block_devices: bool,
+
+    /// Disk image, ISO file, or block device (with --allow-block-devices) to
+    /// attach when a host is connected.
+    ///
+    /// The devices are only attached if no LUNs are configured at the time.
+    /// This can be specified multiple times along with --default-type.
+    #[arg(long, value_name = "FILE", requires = "default_type")]
+    default_file: Vec<PathBuf>,
+
+    /// Mass storage device type of the corresponding --default-file.
+    #[arg(long, value_name = "TYPE", requires = "default_file")]
+    default_type: Vec<MassStorageType>,
+
+    /// When to back LUNs with loop devices.
+    ///
+    /// Setting up a LUN normally requires the kernel to reopen the file, which
+    /// is not possible for some files, like those provided by cloud storage
+    /// apps. A loop device uses the file without reopening it. This requires
+    /// Linux 5.8 or newer.
+    #[arg(long, value_name = "MODE", default_value = "never")]
+    loop_device: LoopMode,
+
+    /// Access files on emulated storage without going through FUSE.
+    ///
+    /// Files that clients open via /storage/emulated are handled by Android's
+    /// FUSE daemon, which is slow. With this option, the equivalent file under
+    /// /data/media is used instead if it is the same file.
+    #[arg(long)]
+    bypass_fuse: bool,
+
+    /// (Internal) Run as the watchdog process for a parent daemon process.
+    #[arg(long, hide = true)]
+    watchdog: bool,
+}
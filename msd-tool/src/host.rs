@@ -0,0 +1,326 @@
+// SPDX-FileCopyrightText: 2026 Andrew Gunnerson
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::time::{Duration, Instant};
+
+use crate::{message::HostState, uevent::Uevent};
+
+impl HostState {
+    /// Map the value of `/sys/class/udc/<controller>/state` to a host state.
+    pub fn from_udc_state(state: &str) -> Self {
+        match state {
+            "not attached" => Self::Disconnected,
+            "configured" => Self::Configured,
+            "suspended" => Self::Suspended,
+            // attached, powered, reconnecting, unauthenticated, default,
+            // addressed.
+            _ => Self::Connected,
+        }
+    }
+
+    /// Map the `USB_STATE` value of an `android_usb` uevent to a host state.
+    pub fn from_android_usb_state(state: &str) -> Option<Self> {
+        match state {
+            "DISCONNECTED" => Some(Self::Disconnected),
+            "CONNECTED" => Some(Self::Connected),
+            "CONFIGURED" => Some(Self::Configured),
+            _ => None,
+        }
+    }
+}
+
+/// A uevent that is relevant to the USB host connection state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostEvent {
+    /// The host state is known from the uevent itself.
+    State(HostState),
+    /// The USB controller's state changed and needs to be queried.
+    ControllerChanged,
+}
+
+impl HostEvent {
+    /// Extract the host event from a uevent. Only `android_usb` events and
+    /// `udc` events for the specified controller are considered.
+    pub fn from_uevent(uevent: &Uevent, controller: &str) -> Option<Self> {
+        match uevent.subsystem()? {
+            "android_usb" => uevent
+                .get("USB_STATE")
+                .and_then(HostState::from_android_usb_state)
+                .map(Self::State),
+            "udc" => {
+                let name = uevent.devpath.rsplit('/').next()?;
+                if name != controller {
+                    None
+                } else if uevent.action == "remove" {
+                    Some(Self::State(HostState::Disconnected))
+                } else {
+                    Some(Self::ControllerChanged)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// State machine tracking the connection to the USB host.
+#[derive(Debug, Clone)]
+pub struct HostStatus {
+    state: HostState,
+    changed: Instant,
+    connect_count: u32,
+    configure_count: u32,
+}
+
+impl HostStatus {
+    pub fn new(state: HostState) -> Self {
+        Self {
+            state,
+            changed: Instant::now(),
+            connect_count: 0,
+            configure_count: 0,
+        }
+    }
+
+    pub fn state(&self) -> HostState {
+        self.state
+    }
+
+    /// How long the host has been in the current state.
+    pub fn duration(&self) -> Duration {
+        self.changed.elapsed()
+    }
+
+    /// Number of times a host was connected after being disconnected.
+    pub fn connect_count(&self) -> u32 {
+        self.connect_count
+    }
+
+    /// Number of times the host configured the device. Each host reset or
+    /// re-enumeration causes the device to be configured again.
+    pub fn configure_count(&self) -> u32 {
+        self.configure_count
+    }
+
+    /// Move to a new state. Returns the previous state if the state changed.
+    pub fn transition(&mut self, state: HostState) -> Option<HostState> {
+        if state == self.state {
+            return None;
+        }
+
+        let old_state = self.state;
+
+        if old_state == HostState::Disconnected {
+            self.connect_count = self.connect_count.saturating_add(1);
+        }
+
+        // Resuming from suspend does not involve configuring the device again.
+        if state == HostState::Configured && old_state != HostState::Suspended {
+            self.configure_count = self.configure_count.saturating_add(1);
+        }
+
+        self.state = state;
+        self.changed = Instant::now();
+
+        Some(old_state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTROLLER: &str = "a600000.dwc3";
+
+    fn uevent(data: &[u8]) -> Uevent {
+        Uevent::parse(data).unwrap()
+    }
+
+    #[test]
+    fn android_usb_events() {
+        let event = |state: &str| {
+            let data = format!(
+                "change@/devices/virtual/android_usb/android0\0\
+                SUBSYSTEM=android_usb\0USB_STATE={state}\0"
+            );
+            HostEvent::from_uevent(&uevent(data.as_bytes()), CONTROLLER)
+        };
+
+        assert_eq!(
+            event("DISCONNECTED"),
+            Some(HostEvent::State(HostState::Disconnected)),
+        );
+        assert_eq!(
+            event("CONNECTED"),
+            Some(HostEvent::State(HostState::Connected)),
+        );
+        assert_eq!(
+            event("CONFIGURED"),
+            Some(HostEvent::State(HostState::Configured)),
+        );
+        assert_eq!(event("SUSPENDED"), None);
+        assert_eq!(event(""), None);
+
+        let missing =
+            uevent(b"change@/devices/virtual/android_usb/android0\0SUBSYSTEM=android_usb\0");
+        assert_eq!(HostEvent::from_uevent(&missing, CONTROLLER), None);
+    }
+
+    #[test]
+    fn udc_events() {
+        let event = |action: &str, name: &str| {
+            let data = format!("{action}@/devices/platform/soc/{name}/udc/{name}\0SUBSYSTEM=udc\0");
+            HostEvent::from_uevent(&uevent(data.as_bytes()), CONTROLLER)
+        };
+
+        assert_eq!(
+            event("change", CONTROLLER),
+            Some(HostEvent::ControllerChanged),
+        );
+        assert_eq!(event("add", CONTROLLER), Some(HostEvent::ControllerChanged));
+        assert_eq!(
+            event("remove", CONTROLLER),
+            Some(HostEvent::State(HostState::Disconnected)),
+        );
+        assert_eq!(event("change", "dummy_udc.0"), None);
+        assert_eq!(event("remove", "dummy_udc.0"), None);
+    }
+
+    #[test]
+    fn ignored_events() {
+        let events: &[&[u8]] = &[
+            // No subsystem.
+            b"change@/devices/virtual/android_usb/android0\0USB_STATE=CONFIGURED\0",
+            b"change@/devices/platform/soc/a600000.dwc3/udc/a600000.dwc3\0",
+            // Unrelated subsystem.
+            b"change@/devices/virtual/power_supply/usb\0SUBSYSTEM=power_supply\0",
+        ];
+
+        for data in events {
+            assert_eq!(HostEvent::from_uevent(&uevent(data), CONTROLLER), None);
+        }
+    }
+
+    #[test]
+    fn udc_states() {
+        assert_eq!(
+            HostState::from_udc_state("not attached"),
+            HostState::Disconnected,
+        );
+        assert_eq!(
+            HostState::from_udc_state("configured"),
+            HostState::Configured,
+        );
+        assert_eq!(HostState::from_udc_state("suspended"), HostState::Suspended);
+
+        for state in ["attached", "powered", "default", "addressed"] {
+            assert_eq!(HostState::from_udc_state(state), HostState::Connected);
+        }
+    }
+
+    #[test]
+    fn suspend_resume() {
+        let mut status = HostStatus::new(HostState::Disconnected);
+
+        for state in ["attached", "default", "addressed", "configured"] {
+            status.transition(HostState::from_udc_state(state));
+        }
+        assert_eq!(status.state(), HostState::Configured);
+        assert_eq!(status.connect_count(), 1);
+        assert_eq!(status.configure_count(), 1);
+
+        // Several suspend/resume cycles, like when the host goes to sleep and
+        // wakes up again, are neither reconnections nor reconfigurations.
+        for _ in 0..3 {
+            assert_eq!(
+                status.transition(HostState::from_udc_state("suspended")),
+                Some(HostState::Configured),
+            );
+            assert_eq!(status.state(), HostState::Suspended);
+
+            // Repeated notifications for the same state are not transitions.
+            assert_eq!(
+                status.transition(HostState::from_udc_state("suspended")),
+                None,
+            );
+
+            assert_eq!(
+                status.transition(HostState::from_udc_state("configured")),
+                Some(HostState::Suspended),
+            );
+            assert_eq!(status.state(), HostState::Configured);
+        }
+
+        assert_eq!(status.connect_count(), 1);
+        assert_eq!(status.configure_count(), 1);
+
+        // Unplugging the cable while suspended is a disconnection.
+        assert_eq!(
+            status.transition(HostState::from_udc_state("suspended")),
+            Some(HostState::Configured),
+        );
+        assert_eq!(
+            status.transition(HostState::from_udc_state("not attached")),
+            Some(HostState::Suspended),
+        );
+        assert_eq!(status.state(), HostState::Disconnected);
+    }
+
+    #[test]
+    fn transition_order() {
+        let mut status = HostStatus::new(HostState::Disconnected);
+        assert_eq!(status.state(), HostState::Disconnected);
+        assert_eq!(status.connect_count(), 0);
+        assert_eq!(status.configure_count(), 0);
+
+        // Staying in the same state is not a transition.
+        assert_eq!(status.transition(HostState::Disconnected), None);
+        assert_eq!(status.connect_count(), 0);
+
+        assert_eq!(
+            status.transition(HostState::Connected),
+            Some(HostState::Disconnected),
+        );
+        assert_eq!(
+            status.transition(HostState::Configured),
+            Some(HostState::Connected),
+        );
+        assert_eq!(status.state(), HostState::Configured);
+        assert_eq!(status.connect_count(), 1);
+        assert_eq!(status.configure_count(), 1);
+
+        // Resuming from suspend is not a new configuration.
+        assert_eq!(
+            status.transition(HostState::Suspended),
+            Some(HostState::Configured),
+        );
+        assert_eq!(
+            status.transition(HostState::Configured),
+            Some(HostState::Suspended),
+        );
+        assert_eq!(status.configure_count(), 1);
+
+        // A host reset re-enumerates the device without disconnecting.
+        assert_eq!(
+            status.transition(HostState::Connected),
+            Some(HostState::Configured),
+        );
+        assert_eq!(
+            status.transition(HostState::Configured),
+            Some(HostState::Connected),
+        );
+        assert_eq!(status.connect_count(), 1);
+        assert_eq!(status.configure_count(), 2);
+
+        // The UDC can report configured straight after disconnecting.
+        assert_eq!(
+            status.transition(HostState::Disconnected),
+            Some(HostState::Configured),
+        );
+        assert_eq!(
+            status.transition(HostState::Configured),
+            Some(HostState::Disconnected),
+        );
+        assert_eq!(status.connect_count(), 2);
+        assert_eq!(status.configure_count(), 3);
+    }
+}
@@ -0,0 +1,208 @@
+// SPDX-FileCopyrightText: 2026 Andrew Gunnerson
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    ffi::c_void,
+    fs::File,
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    path::{Path, PathBuf},
+    ptr, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use rustix::{
+    fs::FileType,
+    io::Errno,
+    ioctl::{self, Ioctl, IoctlOutput, Opcode, Setter},
+};
+use tracing::debug;
+
+const LOOP_CONTROL: &str = "/dev/loop-control";
+/// Android creates the device nodes in /dev/block instead of /dev.
+const LOOP_DIRS: [&str; 2] = ["/dev/block", "/dev"];
+
+const LOOP_CTL_GET_FREE: Opcode = 0x4c82;
+const LOOP_CONFIGURE: Opcode = 0x4c0a;
+
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+
+const LO_NAME_SIZE: usize = 64;
+const LO_KEY_SIZE: usize = 32;
+
+/// Another process may claim the free loop device before we configure it.
+const MAX_ATTEMPTS: usize = 5;
+/// ueventd creates the device nodes for newly allocated loop devices
+/// asynchronously.
+const NODE_TIMEOUT: Duration = Duration::from_secs(1);
+const NODE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// `struct loop_info64` from `<linux/loop.h>`.
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; LO_NAME_SIZE],
+    lo_crypt_name: [u8; LO_NAME_SIZE],
+    lo_encrypt_key: [u8; LO_KEY_SIZE],
+    lo_init: [u64; 2],
+}
+
+/// `struct loop_config` from `<linux/loop.h>`.
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+/// `LOOP_CTL_GET_FREE` returns the device number instead of writing it to a
+/// pointer, so none of rustix's ioctl patterns apply.
+struct GetFree;
+
+unsafe impl Ioctl for GetFree {
+    type Output = u32;
+
+    const IS_MUTATING: bool = false;
+
+    fn opcode(&self) -> Opcode {
+        LOOP_CTL_GET_FREE
+    }
+
+    fn as_ptr(&mut self) -> *mut c_void {
+        ptr::null_mut()
+    }
+
+    unsafe fn output_from_ptr(out: IoctlOutput, _: *mut c_void) -> rustix::io::Result<u32> {
+        Ok(out as u32)
+    }
+}
+
+/// Open the device node for a loop device, waiting for it to be created if
+/// needed.
+fn open_node(number: u32, ro: bool) -> Result<(PathBuf, File)> {
+    let deadline = Instant::now() + NODE_TIMEOUT;
+
+    loop {
+        for dir in LOOP_DIRS {
+            let path = Path::new(dir).join(format!("loop{number}"));
+
+            match File::options().read(true).write(!ro).open(&path) {
+                Ok(file) => {
+                    let stat = rustix::fs::fstat(&file)
+                        .with_context(|| format!("Failed to stat file: {path:?}"))?;
+                    if FileType::from_raw_mode(stat.st_mode) != FileType::BlockDevice {
+                        bail!("Not a block device: {path:?}");
+                    }
+
+                    return Ok((path, file));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to open file: {path:?}"));
+                }
+            }
+        }
+
+        if Instant::now() >= deadline {
+            bail!("Device node for loop device #{number} was never created");
+        }
+
+        thread::sleep(NODE_POLL_INTERVAL);
+    }
+}
+
+/// A loop device backed by an already open file. The kernel detaches the loop
+/// device automatically once nothing has it open anymore.
+#[derive(Debug)]
+pub struct LoopDevice {
+    fd: OwnedFd,
+    path: PathBuf,
+}
+
+impl LoopDevice {
+    /// Attach a free loop device to the file. Unlike setting the file of a
+    /// LUN, this uses the file as is without reopening it. This requires
+    /// `LOOP_CONFIGURE`, which was added in Linux 5.8.
+    pub fn attach(file: BorrowedFd, ro: bool) -> Result<Self> {
+        let control = File::open(LOOP_CONTROL)
+            .with_context(|| format!("Failed to open file: {LOOP_CONTROL:?}"))?;
+
+        for _ in 0..MAX_ATTEMPTS {
+            // SAFETY: GetFree does not pass any data to the kernel.
+            let number = unsafe { ioctl::ioctl(&control, GetFree) }
+                .context("Failed to find free loop device")?;
+            let (path, device) = open_node(number, ro)?;
+
+            let mut flags = LO_FLAGS_AUTOCLEAR;
+            if ro {
+                flags |= LO_FLAGS_READ_ONLY;
+            }
+
+            let config = LoopConfig {
+                fd: file.as_raw_fd() as u32,
+                // Use the kernel's default logical block size.
+                block_size: 0,
+                info: LoopInfo64 {
+                    lo_device: 0,
+                    lo_inode: 0,
+                    lo_rdevice: 0,
+                    lo_offset: 0,
+                    lo_sizelimit: 0,
+                    lo_number: 0,
+                    lo_encrypt_type: 0,
+                    lo_encrypt_key_size: 0,
+                    lo_flags: flags,
+                    lo_file_name: [0; LO_NAME_SIZE],
+                    lo_crypt_name: [0; LO_NAME_SIZE],
+                    lo_encrypt_key: [0; LO_KEY_SIZE],
+                    lo_init: [0; 2],
+                },
+                reserved: [0; 8],
+            };
+
+            // SAFETY: LOOP_CONFIGURE takes a pointer to a struct loop_config.
+            let ret =
+                unsafe { ioctl::ioctl(&device, Setter::<LOOP_CONFIGURE, LoopConfig>::new(config)) };
+
+            match ret {
+                Ok(()) => {
+                    debug!("Attached loop device: {path:?}");
+                    return Ok(Self {
+                        fd: device.into(),
+                        path,
+                    });
+                }
+                Err(Errno::BUSY) => {
+                    debug!("Loop device was claimed by another process: {path:?}");
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to configure loop device: {path:?}"));
+                }
+            }
+        }
+
+        bail!("Failed to claim a free loop device after {MAX_ATTEMPTS} attempts");
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsFd for LoopDevice {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
@@ -1,10 +1,16 @@
 // SPDX-FileCopyrightText: 2024 Andrew Gunnerson
 // SPDX-License-Identifier: GPL-3.0-only
 
+mod activity;
 mod client;
 mod daemon;
+mod host;
+mod loopdev;
 mod message;
+mod persist;
+mod power;
 mod sepatch;
+mod uevent;
 mod usb;
 mod util;
 
@@ -17,6 +17,7 @@ use std::{
 };
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use clap::ValueEnum;
 use rustix::{
     io::Errno,
     net::{
@@ -25,7 +26,7 @@ use rustix::{
     },
 };
 
-pub const PROTOCOL_VERSION: u8 = 1;
+pub const PROTOCOL_VERSION: u8 = 2;
 
 /// Send a list of fds to a unix socket via ancillary data attached to a single
 /// byte message.
@@ -118,6 +119,13 @@ fn read_data(stream: &mut UnixStream) -> io::Result<Vec<u8>> {
     Ok(buf)
 }
 
+/// Read length-prefixed UTF-8 data from the socket.
+fn read_string(stream: &mut UnixStream) -> io::Result<String> {
+    let data = read_data(stream)?;
+
+    String::from_utf8(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
 /// Write a length-prefixed data to the socket.
 fn write_data(stream: &mut UnixStream, buf: &[u8]) -> io::Result<()> {
     if buf.len() > u16::MAX.into() {
@@ -160,9 +168,7 @@ impl MessageId for ErrorResponse {
 
 impl FromSocket for ErrorResponse {
     fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
-        let data = read_data(stream)?;
-        let message =
-            String::from_utf8(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let message = read_string(stream)?;
 
         Ok(Self { message })
     }
@@ -237,11 +243,34 @@ impl ToSocket for GetFunctionsResponse {
     }
 }
 
+/// Mass storage device type as selected on the command line. This maps to the
+/// `cdrom` and `ro` attributes of a LUN.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MassStorageType {
+    Cdrom,
+    DiskRo,
+    DiskRw,
+}
+
+impl MassStorageType {
+    pub fn new(cdrom: bool, ro: bool) -> Self {
+        match (cdrom, ro) {
+            (true, _) => Self::Cdrom,
+            (false, true) => Self::DiskRo,
+            (false, false) => Self::DiskRw,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct MassStorageDevice {
     pub fd: OwnedFd,
     pub cdrom: bool,
     pub ro: bool,
+    pub removable: bool,
+    pub nofua: bool,
+    /// Empty to use the kernel's default.
+    pub inquiry_string: String,
 }
 
 impl FromSocket for MassStorageDevice {
@@ -249,8 +278,18 @@ impl FromSocket for MassStorageDevice {
         let fd = receive_fds(stream, 1)?.pop().unwrap();
         let cdrom = stream.read_u8()? != 0;
         let ro = stream.read_u8()? != 0;
-
-        Ok(Self { fd, cdrom, ro })
+        let removable = stream.read_u8()? != 0;
+        let nofua = stream.read_u8()? != 0;
+        let inquiry_string = read_string(stream)?;
+
+        Ok(Self {
+            fd,
+            cdrom,
+            ro,
+            removable,
+            nofua,
+            inquiry_string,
+        })
     }
 }
 
@@ -259,14 +298,231 @@ impl ToSocket for MassStorageDevice {
         send_fds(stream, &[self.fd.as_fd()])?;
         stream.write_u8(self.cdrom.into())?;
         stream.write_u8(self.ro.into())?;
+        stream.write_u8(self.removable.into())?;
+        stream.write_u8(self.nofua.into())?;
+        write_data(stream, self.inquiry_string.as_bytes())?;
 
         Ok(())
     }
 }
 
+/// Condition under which the daemon automatically clears the LUNs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lease {
+    /// The LUNs persist until they are explicitly cleared.
+    #[default]
+    None,
+    /// The LUNs are cleared when the client's connection closes.
+    Connection,
+    /// The LUNs are cleared unless the lease is renewed within the timeout.
+    Timeout { timeout_ms: u32 },
+}
+
+impl FromSocket for Lease {
+    fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let kind = stream.read_u8()?;
+        let timeout_ms = stream.read_u32::<LittleEndian>()?;
+
+        match kind {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Connection),
+            2 => Ok(Self::Timeout { timeout_ms }),
+            n => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid lease type: {n}"),
+            )),
+        }
+    }
+}
+
+impl ToSocket for Lease {
+    fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        let (kind, timeout_ms) = match self {
+            Self::None => (0, 0),
+            Self::Connection => (1, 0),
+            Self::Timeout { timeout_ms } => (2, *timeout_ms),
+        };
+
+        stream.write_u8(kind)?;
+        stream.write_u32::<LittleEndian>(timeout_ms)?;
+
+        Ok(())
+    }
+}
+
+/// Read an optional u16 that is prefixed by a presence flag.
+fn read_option_u16(stream: &mut UnixStream) -> io::Result<Option<u16>> {
+    let present = stream.read_u8()? != 0;
+    let value = stream.read_u16::<LittleEndian>()?;
+
+    Ok(Some(value).filter(|_| present))
+}
+
+/// Write an optional u16 that is prefixed by a presence flag.
+fn write_option_u16(stream: &mut UnixStream, value: Option<u16>) -> io::Result<()> {
+    stream.write_u8(value.is_some().into())?;
+    stream.write_u16::<LittleEndian>(value.unwrap_or_default())?;
+
+    Ok(())
+}
+
+/// Read an optional u32 that is prefixed by a presence flag.
+fn read_option_u32(stream: &mut UnixStream) -> io::Result<Option<u32>> {
+    let present = stream.read_u8()? != 0;
+    let value = stream.read_u32::<LittleEndian>()?;
+
+    Ok(Some(value).filter(|_| present))
+}
+
+/// Write an optional u32 that is prefixed by a presence flag.
+fn write_option_u32(stream: &mut UnixStream, value: Option<u32>) -> io::Result<()> {
+    stream.write_u8(value.is_some().into())?;
+    stream.write_u32::<LittleEndian>(value.unwrap_or_default())?;
+
+    Ok(())
+}
+
+/// Read an optional u64 that is prefixed by a presence flag.
+fn read_option_u64(stream: &mut UnixStream) -> io::Result<Option<u64>> {
+    let present = stream.read_u8()? != 0;
+    let value = stream.read_u64::<LittleEndian>()?;
+
+    Ok(Some(value).filter(|_| present))
+}
+
+/// Write an optional u64 that is prefixed by a presence flag.
+fn write_option_u64(stream: &mut UnixStream, value: Option<u64>) -> io::Result<()> {
+    stream.write_u8(value.is_some().into())?;
+    stream.write_u64::<LittleEndian>(value.unwrap_or_default())?;
+
+    Ok(())
+}
+
+/// Read optional length-prefixed UTF-8 data that is prefixed by a presence
+/// flag.
+fn read_option_string(stream: &mut UnixStream) -> io::Result<Option<String>> {
+    let present = stream.read_u8()? != 0;
+    let value = read_string(stream)?;
+
+    Ok(Some(value).filter(|_| present))
+}
+
+/// Write optional length-prefixed UTF-8 data that is prefixed by a presence
+/// flag.
+fn write_option_string(stream: &mut UnixStream, value: Option<&str>) -> io::Result<()> {
+    stream.write_u8(value.is_some().into())?;
+    write_data(stream, value.unwrap_or_default().as_bytes())?;
+
+    Ok(())
+}
+
+/// USB device descriptor fields to override while mass storage is active.
+/// Fields that are None keep the gadget's original values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DescriptorOverrides {
+    pub id_vendor: Option<u16>,
+    pub id_product: Option<u16>,
+    pub bcd_device: Option<u16>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+impl DescriptorOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl FromSocket for DescriptorOverrides {
+    fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let id_vendor = read_option_u16(stream)?;
+        let id_product = read_option_u16(stream)?;
+        let bcd_device = read_option_u16(stream)?;
+        let manufacturer = read_option_string(stream)?;
+        let product = read_option_string(stream)?;
+        let serial_number = read_option_string(stream)?;
+
+        Ok(Self {
+            id_vendor,
+            id_product,
+            bcd_device,
+            manufacturer,
+            product,
+            serial_number,
+        })
+    }
+}
+
+impl ToSocket for DescriptorOverrides {
+    fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        write_option_u16(stream, self.id_vendor)?;
+        write_option_u16(stream, self.id_product)?;
+        write_option_u16(stream, self.bcd_device)?;
+        write_option_string(stream, self.manufacturer.as_deref())?;
+        write_option_string(stream, self.product.as_deref())?;
+        write_option_string(stream, self.serial_number.as_deref())?;
+
+        Ok(())
+    }
+}
+
+/// Maximum USB speed to advertise to the host. Some old BIOSes fail to boot
+/// from SuperSpeed devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeedLimit {
+    HighSpeed,
+    FullSpeed,
+}
+
+impl SpeedLimit {
+    fn from_u8(value: u8) -> io::Result<Option<Self>> {
+        match value {
+            0 => Ok(None),
+            1 => Ok(Some(Self::HighSpeed)),
+            2 => Ok(Some(Self::FullSpeed)),
+            n => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid speed limit: {n}"),
+            )),
+        }
+    }
+
+    fn to_u8(limit: Option<Self>) -> u8 {
+        match limit {
+            None => 0,
+            Some(Self::HighSpeed) => 1,
+            Some(Self::FullSpeed) => 2,
+        }
+    }
+
+    /// The value of the gadget's `max_speed` configfs attribute.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HighSpeed => "high-speed",
+            Self::FullSpeed => "full-speed",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SetMassStorageRequest {
     pub devices: Vec<MassStorageDevice>,
+    pub lease: Lease,
+    /// Replace LUNs owned by other clients even if the daemon enforces
+    /// ownership.
+    pub force: bool,
+    /// Whether the function is allowed to halt bulk endpoints.
+    pub stall: bool,
+    pub descriptors: DescriptorOverrides,
+    /// None to use the gadget's original maximum speed.
+    pub max_speed: Option<SpeedLimit>,
+    /// Clear the LUNs after this long without host activity. 0 to use the
+    /// daemon's default.
+    pub idle_timeout_ms: u32,
+    /// Clear the LUNs after the host resets the device this many times or
+    /// ejects the media. If 0, the LUNs are only cleared on ejection.
+    pub one_shot: Option<u32>,
 }
 
 impl MessageId for SetMassStorageRequest {
@@ -283,7 +539,24 @@ impl FromSocket for SetMassStorageRequest {
             devices.push(device);
         }
 
-        Ok(Self { devices })
+        let lease = Lease::from_socket(stream)?;
+        let force = stream.read_u8()? != 0;
+        let stall = stream.read_u8()? != 0;
+        let descriptors = DescriptorOverrides::from_socket(stream)?;
+        let max_speed = SpeedLimit::from_u8(stream.read_u8()?)?;
+        let idle_timeout_ms = stream.read_u32::<LittleEndian>()?;
+        let one_shot = read_option_u32(stream)?;
+
+        Ok(Self {
+            devices,
+            lease,
+            force,
+            stall,
+            descriptors,
+            max_speed,
+            idle_timeout_ms,
+            one_shot,
+        })
     }
 }
 
@@ -301,6 +574,14 @@ impl ToSocket for SetMassStorageRequest {
             device.to_socket(stream)?;
         }
 
+        self.lease.to_socket(stream)?;
+        stream.write_u8(self.force.into())?;
+        stream.write_u8(self.stall.into())?;
+        self.descriptors.to_socket(stream)?;
+        stream.write_u8(SpeedLimit::to_u8(self.max_speed))?;
+        stream.write_u32::<LittleEndian>(self.idle_timeout_ms)?;
+        write_option_u32(stream, self.one_shot)?;
+
         Ok(())
     }
 }
@@ -324,30 +605,166 @@ impl ToSocket for SetMassStorageResponse {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LunState {
+    /// The LUN is backed by a file and is visible to the host.
+    Active,
+    /// The host ejected the media and the kernel closed the file.
+    EjectedByHost,
+    /// The daemon ejected the media because the file was modified by
+    /// something other than the host.
+    EjectedOnChange,
+}
+
+impl LunState {
+    fn from_u8(value: u8) -> io::Result<Self> {
+        match value {
+            0 => Ok(Self::Active),
+            1 => Ok(Self::EjectedByHost),
+            2 => Ok(Self::EjectedOnChange),
+            n => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid LUN state: {n}"),
+            )),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Active => 0,
+            Self::EjectedByHost => 1,
+            Self::EjectedOnChange => 2,
+        }
+    }
+}
+
+/// The client that configured a LUN.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LunOwner {
+    pub uid: u32,
+    /// SELinux context of the client. This is None if it could not be read.
+    pub label: Option<String>,
+    /// Client-supplied name. This is empty if the client did not set one.
+    pub name: String,
+}
+
+impl FromSocket for LunOwner {
+    fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let uid = stream.read_u32::<LittleEndian>()?;
+        let label = Some(read_string(stream)?).filter(|s| !s.is_empty());
+        let name = read_string(stream)?;
+
+        Ok(Self { uid, label, name })
+    }
+}
+
+impl ToSocket for LunOwner {
+    fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        stream.write_u32::<LittleEndian>(self.uid)?;
+        write_data(stream, self.label.as_deref().unwrap_or_default().as_bytes())?;
+        write_data(stream, self.name.as_bytes())?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct ActiveMassStorageDevice {
+    pub lun: u8,
     pub file: PathBuf,
     pub cdrom: bool,
     pub ro: bool,
+    pub removable: bool,
+    pub nofua: bool,
+    pub inquiry_string: String,
+    pub state: LunState,
+    /// None if the LUN was not configured by the running daemon instance.
+    pub owner: Option<LunOwner>,
+    /// 0 if the LUN is not cleared after a period of inactivity.
+    pub idle_timeout_ms: u32,
+    /// How long it has been since the host last accessed the gadget.
+    pub idle_ms: u64,
+    /// How long it has been since the host last read from the LUN. None if it
+    /// never did or if this is unknown.
+    pub last_read_ms: Option<u64>,
+    /// How long it has been since the host last wrote to the LUN. None if it
+    /// never did or if this is unknown.
+    pub last_write_ms: Option<u64>,
+    /// Bytes read since the LUN was configured. This is only known for LUNs
+    /// backed by block devices, including loop devices, and never for regular
+    /// files. The kernel's counters include all reads of the block device, not
+    /// just those by the host.
+    pub read_bytes: Option<u64>,
+    /// Bytes written since the LUN was configured. Like `read_bytes`, this is
+    /// never known for regular files and includes all writes to the block
+    /// device, not just those by the host.
+    pub written_bytes: Option<u64>,
 }
 
 impl FromSocket for ActiveMassStorageDevice {
     fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let lun = stream.read_u8()?;
         let file = read_data(stream)
             .map(OsString::from_vec)
             .map(PathBuf::from)?;
         let cdrom = stream.read_u8()? != 0;
         let ro = stream.read_u8()? != 0;
-
-        Ok(Self { file, cdrom, ro })
+        let removable = stream.read_u8()? != 0;
+        let nofua = stream.read_u8()? != 0;
+        let inquiry_string = read_string(stream)?;
+        let state = LunState::from_u8(stream.read_u8()?)?;
+        let owner = if stream.read_u8()? != 0 {
+            Some(LunOwner::from_socket(stream)?)
+        } else {
+            None
+        };
+        let idle_timeout_ms = stream.read_u32::<LittleEndian>()?;
+        let idle_ms = stream.read_u64::<LittleEndian>()?;
+        let last_read_ms = read_option_u64(stream)?;
+        let last_write_ms = read_option_u64(stream)?;
+        let read_bytes = read_option_u64(stream)?;
+        let written_bytes = read_option_u64(stream)?;
+
+        Ok(Self {
+            lun,
+            file,
+            cdrom,
+            ro,
+            removable,
+            nofua,
+            inquiry_string,
+            state,
+            owner,
+            idle_timeout_ms,
+            idle_ms,
+            last_read_ms,
+            last_write_ms,
+            read_bytes,
+            written_bytes,
+        })
     }
 }
 
 impl ToSocket for ActiveMassStorageDevice {
     fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        stream.write_u8(self.lun)?;
         write_data(stream, self.file.as_os_str().as_bytes())?;
         stream.write_u8(self.cdrom.into())?;
         stream.write_u8(self.ro.into())?;
+        stream.write_u8(self.removable.into())?;
+        stream.write_u8(self.nofua.into())?;
+        write_data(stream, self.inquiry_string.as_bytes())?;
+        stream.write_u8(self.state.to_u8())?;
+        stream.write_u8(self.owner.is_some().into())?;
+        if let Some(owner) = &self.owner {
+            owner.to_socket(stream)?;
+        }
+        stream.write_u32::<LittleEndian>(self.idle_timeout_ms)?;
+        stream.write_u64::<LittleEndian>(self.idle_ms)?;
+        write_option_u64(stream, self.last_read_ms)?;
+        write_option_u64(stream, self.last_write_ms)?;
+        write_option_u64(stream, self.read_bytes)?;
+        write_option_u64(stream, self.written_bytes)?;
 
         Ok(())
     }
@@ -413,11 +830,472 @@ impl ToSocket for GetMassStorageResponse {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct GetControllerStateRequest;
+
+impl MessageId for GetControllerStateRequest {
+    const ID: u8 = 8;
+}
+
+impl FromSocket for GetControllerStateRequest {
+    fn from_socket(_stream: &mut UnixStream) -> io::Result<Self> {
+        Ok(Self)
+    }
+}
+
+impl ToSocket for GetControllerStateRequest {
+    fn to_socket(&self, _stream: &mut UnixStream) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// USB host connection state as tracked by the daemon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostState {
+    Disconnected,
+    Connected,
+    Configured,
+    Suspended,
+}
+
+impl HostState {
+    fn from_u8(value: u8) -> io::Result<Option<Self>> {
+        match value {
+            0 => Ok(None),
+            1 => Ok(Some(Self::Disconnected)),
+            2 => Ok(Some(Self::Connected)),
+            3 => Ok(Some(Self::Configured)),
+            4 => Ok(Some(Self::Suspended)),
+            n => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid host state: {n}"),
+            )),
+        }
+    }
+
+    fn to_u8(state: Option<Self>) -> u8 {
+        match state {
+            None => 0,
+            Some(Self::Disconnected) => 1,
+            Some(Self::Connected) => 2,
+            Some(Self::Configured) => 3,
+            Some(Self::Suspended) => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GetControllerStateResponse {
+    /// The USB controller that the daemon uses.
+    pub controller: String,
+    /// The USB controller that the gadget is currently bound to.
+    pub bound_controller: Option<String>,
+    pub state: String,
+    pub current_speed: String,
+    pub maximum_speed: String,
+    /// The maximum speed advertised by the gadget. None if the kernel does not
+    /// support limiting the gadget's speed.
+    pub gadget_max_speed: Option<String>,
+    /// Whether the daemon is holding its wake lock. None if the daemon does
+    /// not manage a wake lock.
+    pub wake_lock_held: Option<bool>,
+    pub is_otg: bool,
+    /// None if the daemon is unable to monitor uevents.
+    pub host_state: Option<HostState>,
+    /// How long the host has been in the current state.
+    pub host_state_duration_ms: u64,
+    pub host_connect_count: u32,
+    pub host_configure_count: u32,
+}
+
+impl MessageId for GetControllerStateResponse {
+    const ID: u8 = 9;
+}
+
+impl FromSocket for GetControllerStateResponse {
+    fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let controller = read_string(stream)?;
+        let bound_controller = Some(read_string(stream)?).filter(|s| !s.is_empty());
+        let state = read_string(stream)?;
+        let current_speed = read_string(stream)?;
+        let maximum_speed = read_string(stream)?;
+        let gadget_max_speed = Some(read_string(stream)?).filter(|s| !s.is_empty());
+        let wake_lock_held = match stream.read_u8()? {
+            0 => None,
+            1 => Some(false),
+            2 => Some(true),
+            n => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid wake lock state: {n}"),
+                ));
+            }
+        };
+        let is_otg = stream.read_u8()? != 0;
+        let host_state = HostState::from_u8(stream.read_u8()?)?;
+        let host_state_duration_ms = stream.read_u64::<LittleEndian>()?;
+        let host_connect_count = stream.read_u32::<LittleEndian>()?;
+        let host_configure_count = stream.read_u32::<LittleEndian>()?;
+
+        Ok(Self {
+            controller,
+            bound_controller,
+            state,
+            current_speed,
+            maximum_speed,
+            gadget_max_speed,
+            wake_lock_held,
+            is_otg,
+            host_state,
+            host_state_duration_ms,
+            host_connect_count,
+            host_configure_count,
+        })
+    }
+}
+
+impl ToSocket for GetControllerStateResponse {
+    fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        write_data(stream, self.controller.as_bytes())?;
+        write_data(
+            stream,
+            self.bound_controller
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        )?;
+        write_data(stream, self.state.as_bytes())?;
+        write_data(stream, self.current_speed.as_bytes())?;
+        write_data(stream, self.maximum_speed.as_bytes())?;
+        write_data(
+            stream,
+            self.gadget_max_speed
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        )?;
+        stream.write_u8(match self.wake_lock_held {
+            None => 0,
+            Some(false) => 1,
+            Some(true) => 2,
+        })?;
+        stream.write_u8(self.is_otg.into())?;
+        stream.write_u8(HostState::to_u8(self.host_state))?;
+        stream.write_u64::<LittleEndian>(self.host_state_duration_ms)?;
+        stream.write_u32::<LittleEndian>(self.host_connect_count)?;
+        stream.write_u32::<LittleEndian>(self.host_configure_count)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectRequest {
+    /// How long to keep the device disconnected.
+    pub delay_ms: u32,
+}
+
+impl MessageId for ReconnectRequest {
+    const ID: u8 = 10;
+}
+
+impl FromSocket for ReconnectRequest {
+    fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let delay_ms = stream.read_u32::<LittleEndian>()?;
+
+        Ok(Self { delay_ms })
+    }
+}
+
+impl ToSocket for ReconnectRequest {
+    fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        stream.write_u32::<LittleEndian>(self.delay_ms)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectResponse;
+
+impl MessageId for ReconnectResponse {
+    const ID: u8 = 11;
+}
+
+impl FromSocket for ReconnectResponse {
+    fn from_socket(_stream: &mut UnixStream) -> io::Result<Self> {
+        Ok(Self)
+    }
+}
+
+impl ToSocket for ReconnectResponse {
+    fn to_socket(&self, _stream: &mut UnixStream) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenewLeaseRequest;
+
+impl MessageId for RenewLeaseRequest {
+    const ID: u8 = 12;
+}
+
+impl FromSocket for RenewLeaseRequest {
+    fn from_socket(_stream: &mut UnixStream) -> io::Result<Self> {
+        Ok(Self)
+    }
+}
+
+impl ToSocket for RenewLeaseRequest {
+    fn to_socket(&self, _stream: &mut UnixStream) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenewLeaseResponse;
+
+impl MessageId for RenewLeaseResponse {
+    const ID: u8 = 13;
+}
+
+impl FromSocket for RenewLeaseResponse {
+    fn from_socket(_stream: &mut UnixStream) -> io::Result<Self> {
+        Ok(Self)
+    }
+}
+
+impl ToSocket for RenewLeaseResponse {
+    fn to_socket(&self, _stream: &mut UnixStream) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SetClientNameRequest {
+    /// Name to identify this client as the owner of the LUNs that it
+    /// configures.
+    pub name: String,
+}
+
+impl MessageId for SetClientNameRequest {
+    const ID: u8 = 14;
+}
+
+impl FromSocket for SetClientNameRequest {
+    fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let name = read_string(stream)?;
+
+        Ok(Self { name })
+    }
+}
+
+impl ToSocket for SetClientNameRequest {
+    fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        write_data(stream, self.name.as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SetClientNameResponse;
+
+impl MessageId for SetClientNameResponse {
+    const ID: u8 = 15;
+}
+
+impl FromSocket for SetClientNameResponse {
+    fn from_socket(_stream: &mut UnixStream) -> io::Result<Self> {
+        Ok(Self)
+    }
+}
+
+impl ToSocket for SetClientNameResponse {
+    fn to_socket(&self, _stream: &mut UnixStream) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct AddLunRequest {
+    pub device: MassStorageDevice,
+    pub lease: Lease,
+    /// Clear the LUN after this long without host activity. 0 to use the
+    /// daemon's default.
+    pub idle_timeout_ms: u32,
+    /// Clear the LUN after the host resets the device this many times or
+    /// ejects the media. If 0, the LUN is only cleared on ejection.
+    pub one_shot: Option<u32>,
+}
+
+impl MessageId for AddLunRequest {
+    const ID: u8 = 16;
+}
+
+impl FromSocket for AddLunRequest {
+    fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let device = MassStorageDevice::from_socket(stream)?;
+        let lease = Lease::from_socket(stream)?;
+        let idle_timeout_ms = stream.read_u32::<LittleEndian>()?;
+        let one_shot = read_option_u32(stream)?;
+
+        Ok(Self {
+            device,
+            lease,
+            idle_timeout_ms,
+            one_shot,
+        })
+    }
+}
+
+impl ToSocket for AddLunRequest {
+    fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        self.device.to_socket(stream)?;
+        self.lease.to_socket(stream)?;
+        stream.write_u32::<LittleEndian>(self.idle_timeout_ms)?;
+        write_option_u32(stream, self.one_shot)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AddLunResponse {
+    /// The LUN that the device was assigned to.
+    pub lun: u8,
+}
+
+impl MessageId for AddLunResponse {
+    const ID: u8 = 17;
+}
+
+impl FromSocket for AddLunResponse {
+    fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let lun = stream.read_u8()?;
+
+        Ok(Self { lun })
+    }
+}
+
+impl ToSocket for AddLunResponse {
+    fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        stream.write_u8(self.lun)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RemoveLunRequest {
+    pub lun: u8,
+    /// Remove the LUN even if it is owned by another client.
+    pub force: bool,
+}
+
+impl MessageId for RemoveLunRequest {
+    const ID: u8 = 18;
+}
+
+impl FromSocket for RemoveLunRequest {
+    fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let lun = stream.read_u8()?;
+        let force = stream.read_u8()? != 0;
+
+        Ok(Self { lun, force })
+    }
+}
+
+impl ToSocket for RemoveLunRequest {
+    fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        stream.write_u8(self.lun)?;
+        stream.write_u8(self.force.into())?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RemoveLunResponse;
+
+impl MessageId for RemoveLunResponse {
+    const ID: u8 = 19;
+}
+
+impl FromSocket for RemoveLunResponse {
+    fn from_socket(_stream: &mut UnixStream) -> io::Result<Self> {
+        Ok(Self)
+    }
+}
+
+impl ToSocket for RemoveLunResponse {
+    fn to_socket(&self, _stream: &mut UnixStream) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ChangeMediaRequest {
+    pub lun: u8,
+    pub device: MassStorageDevice,
+    /// Change the media even if the LUN is owned by another client.
+    pub force: bool,
+}
+
+impl MessageId for ChangeMediaRequest {
+    const ID: u8 = 20;
+}
+
+impl FromSocket for ChangeMediaRequest {
+    fn from_socket(stream: &mut UnixStream) -> io::Result<Self> {
+        let lun = stream.read_u8()?;
+        let device = MassStorageDevice::from_socket(stream)?;
+        let force = stream.read_u8()? != 0;
+
+        Ok(Self { lun, device, force })
+    }
+}
+
+impl ToSocket for ChangeMediaRequest {
+    fn to_socket(&self, stream: &mut UnixStream) -> io::Result<()> {
+        stream.write_u8(self.lun)?;
+        self.device.to_socket(stream)?;
+        stream.write_u8(self.force.into())?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChangeMediaResponse;
+
+impl MessageId for ChangeMediaResponse {
+    const ID: u8 = 21;
+}
+
+impl FromSocket for ChangeMediaResponse {
+    fn from_socket(_stream: &mut UnixStream) -> io::Result<Self> {
+        Ok(Self)
+    }
+}
+
+impl ToSocket for ChangeMediaResponse {
+    fn to_socket(&self, _stream: &mut UnixStream) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum Request {
     GetFunctions(GetFunctionsRequest),
     SetMassStorage(SetMassStorageRequest),
     GetMassStorage(GetMassStorageRequest),
+    GetControllerState(GetControllerStateRequest),
+    Reconnect(ReconnectRequest),
+    RenewLease(RenewLeaseRequest),
+    SetClientName(SetClientNameRequest),
+    AddLun(AddLunRequest),
+    RemoveLun(RemoveLunRequest),
+    ChangeMedia(ChangeMediaRequest),
 }
 
 impl FromSocket for Request {
@@ -434,6 +1312,19 @@ impl FromSocket for Request {
             GetMassStorageRequest::ID => {
                 GetMassStorageRequest::from_socket(stream).map(Self::GetMassStorage)
             }
+            GetControllerStateRequest::ID => {
+                GetControllerStateRequest::from_socket(stream).map(Self::GetControllerState)
+            }
+            ReconnectRequest::ID => ReconnectRequest::from_socket(stream).map(Self::Reconnect),
+            RenewLeaseRequest::ID => RenewLeaseRequest::from_socket(stream).map(Self::RenewLease),
+            SetClientNameRequest::ID => {
+                SetClientNameRequest::from_socket(stream).map(Self::SetClientName)
+            }
+            AddLunRequest::ID => AddLunRequest::from_socket(stream).map(Self::AddLun),
+            RemoveLunRequest::ID => RemoveLunRequest::from_socket(stream).map(Self::RemoveLun),
+            ChangeMediaRequest::ID => {
+                ChangeMediaRequest::from_socket(stream).map(Self::ChangeMedia)
+            }
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!("Invalid message ID: {id}"),
@@ -448,6 +1339,13 @@ impl ToSocket for Request {
             Self::GetFunctions(m) => m.id(),
             Self::SetMassStorage(m) => m.id(),
             Self::GetMassStorage(m) => m.id(),
+            Self::GetControllerState(m) => m.id(),
+            Self::Reconnect(m) => m.id(),
+            Self::RenewLease(m) => m.id(),
+            Self::SetClientName(m) => m.id(),
+            Self::AddLun(m) => m.id(),
+            Self::RemoveLun(m) => m.id(),
+            Self::ChangeMedia(m) => m.id(),
         };
 
         stream.write_u8(id)?;
@@ -456,6 +1354,13 @@ impl ToSocket for Request {
             Self::GetFunctions(m) => m.to_socket(stream),
             Self::SetMassStorage(m) => m.to_socket(stream),
             Self::GetMassStorage(m) => m.to_socket(stream),
+            Self::GetControllerState(m) => m.to_socket(stream),
+            Self::Reconnect(m) => m.to_socket(stream),
+            Self::RenewLease(m) => m.to_socket(stream),
+            Self::SetClientName(m) => m.to_socket(stream),
+            Self::AddLun(m) => m.to_socket(stream),
+            Self::RemoveLun(m) => m.to_socket(stream),
+            Self::ChangeMedia(m) => m.to_socket(stream),
         }
     }
 }
@@ -466,6 +1371,13 @@ pub enum Response {
     GetFunctions(GetFunctionsResponse),
     SetMassStorage(SetMassStorageResponse),
     GetMassStorage(GetMassStorageResponse),
+    GetControllerState(GetControllerStateResponse),
+    Reconnect(ReconnectResponse),
+    RenewLease(RenewLeaseResponse),
+    SetClientName(SetClientNameResponse),
+    AddLun(AddLunResponse),
+    RemoveLun(RemoveLunResponse),
+    ChangeMedia(ChangeMediaResponse),
 }
 
 impl FromSocket for Response {
@@ -483,6 +1395,19 @@ impl FromSocket for Response {
             GetMassStorageResponse::ID => {
                 GetMassStorageResponse::from_socket(stream).map(Self::GetMassStorage)
             }
+            GetControllerStateResponse::ID => {
+                GetControllerStateResponse::from_socket(stream).map(Self::GetControllerState)
+            }
+            ReconnectResponse::ID => ReconnectResponse::from_socket(stream).map(Self::Reconnect),
+            RenewLeaseResponse::ID => RenewLeaseResponse::from_socket(stream).map(Self::RenewLease),
+            SetClientNameResponse::ID => {
+                SetClientNameResponse::from_socket(stream).map(Self::SetClientName)
+            }
+            AddLunResponse::ID => AddLunResponse::from_socket(stream).map(Self::AddLun),
+            RemoveLunResponse::ID => RemoveLunResponse::from_socket(stream).map(Self::RemoveLun),
+            ChangeMediaResponse::ID => {
+                ChangeMediaResponse::from_socket(stream).map(Self::ChangeMedia)
+            }
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!("Invalid message ID: {id}"),
@@ -498,6 +1423,13 @@ impl ToSocket for Response {
             Self::GetFunctions(m) => m.id(),
             Self::SetMassStorage(m) => m.id(),
             Self::GetMassStorage(m) => m.id(),
+            Self::GetControllerState(m) => m.id(),
+            Self::Reconnect(m) => m.id(),
+            Self::RenewLease(m) => m.id(),
+            Self::SetClientName(m) => m.id(),
+            Self::AddLun(m) => m.id(),
+            Self::RemoveLun(m) => m.id(),
+            Self::ChangeMedia(m) => m.id(),
         };
 
         stream.write_u8(id)?;
@@ -507,6 +1439,13 @@ impl ToSocket for Response {
             Self::GetFunctions(m) => m.to_socket(stream),
             Self::SetMassStorage(m) => m.to_socket(stream),
             Self::GetMassStorage(m) => m.to_socket(stream),
+            Self::GetControllerState(m) => m.to_socket(stream),
+            Self::Reconnect(m) => m.to_socket(stream),
+            Self::RenewLease(m) => m.to_socket(stream),
+            Self::SetClientName(m) => m.to_socket(stream),
+            Self::AddLun(m) => m.to_socket(stream),
+            Self::RemoveLun(m) => m.to_socket(stream),
+            Self::ChangeMedia(m) => m.to_socket(stream),
         }
     }
 }
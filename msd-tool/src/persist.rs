@@ -0,0 +1,503 @@
+// SPDX-FileCopyrightText: 2026 Andrew Gunnerson
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    io::{self, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use cap_std::{ambient_authority, fs::Dir};
+
+use crate::{
+    message::{DescriptorOverrides, SpeedLimit},
+    usb::LunAttrs,
+};
+
+const HEADER: &str = "msd-tool-config 1";
+
+/// Default location of the saved configuration. The module's boot script
+/// creates the directory with the label that the SELinux policy from `sepatch`
+/// allows the daemon to write to.
+pub const DEFAULT_PATH: &str = "/data/adb/msd/config";
+
+/// A LUN from the last configuration. The backing file is identified by both
+/// its path and its device and inode numbers so that a file that was replaced
+/// is not restored by accident.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistedLun {
+    pub lun: u8,
+    pub file: PathBuf,
+    pub dev: u64,
+    pub ino: u64,
+    pub attrs: LunAttrs,
+}
+
+/// Gadget-wide settings from the last configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistedGadget {
+    pub stall: bool,
+    pub descriptors: DescriptorOverrides,
+    pub max_speed: Option<SpeedLimit>,
+}
+
+impl Default for PersistedGadget {
+    fn default() -> Self {
+        Self {
+            stall: true,
+            descriptors: DescriptorOverrides::default(),
+            max_speed: None,
+        }
+    }
+}
+
+/// The last configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PersistedConfig {
+    pub gadget: PersistedGadget,
+    pub luns: Vec<PersistedLun>,
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "-".to_owned();
+    }
+
+    data.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+fn decode_hex(data: &str) -> Result<Vec<u8>> {
+    if data == "-" {
+        return Ok(vec![]);
+    } else if data.is_empty() || !data.len().is_multiple_of(2) {
+        bail!("Hex data has invalid length: {data:?}");
+    } else if !data.bytes().all(|b| b.is_ascii_hexdigit()) {
+        // from_str_radix() would otherwise accept a sign.
+        bail!("Invalid hex data: {data:?}");
+    }
+
+    (0..data.len())
+        .step_by(2)
+        .map(|i| {
+            data.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid hex data: {data:?}"))
+        })
+        .collect()
+}
+
+/// Unlike [`encode_hex()`], this distinguishes between an absent string (`-`)
+/// and an empty one (`+`).
+fn encode_option_hex(data: Option<&str>) -> String {
+    match data {
+        Some("") => "+".to_owned(),
+        Some(d) => encode_hex(d.as_bytes()),
+        None => "-".to_owned(),
+    }
+}
+
+fn decode_option_hex(data: &str) -> Result<Option<String>> {
+    let data = match data {
+        "-" => return Ok(None),
+        "+" => vec![],
+        d => decode_hex(d)?,
+    };
+
+    String::from_utf8(data)
+        .map(Some)
+        .context("String is not UTF-8")
+}
+
+fn encode_option_u16(value: Option<u16>) -> String {
+    value.map_or_else(|| "-".to_owned(), |v| format!("{v:04x}"))
+}
+
+fn decode_option_u16(value: &str) -> Result<Option<u16>> {
+    if value == "-" {
+        return Ok(None);
+    } else if !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Invalid 16-bit value: {value:?}");
+    }
+
+    u16::from_str_radix(value, 16)
+        .map(Some)
+        .with_context(|| format!("Invalid 16-bit value: {value:?}"))
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        v => bail!("Invalid boolean: {v:?}"),
+    }
+}
+
+/// Serialize the configuration. The gadget-wide settings are stored on the line
+/// after the header and each LUN is stored on its own line after that. Strings
+/// are hex encoded so that they cannot contain separators.
+pub fn serialize(config: &PersistedConfig) -> String {
+    let mut data = format!("{HEADER}\n");
+
+    let gadget = &config.gadget;
+    let _ = writeln!(
+        data,
+        "{} {} {} {} {} {} {} {}",
+        u8::from(gadget.stall),
+        encode_option_u16(gadget.descriptors.id_vendor),
+        encode_option_u16(gadget.descriptors.id_product),
+        encode_option_u16(gadget.descriptors.bcd_device),
+        encode_option_hex(gadget.descriptors.manufacturer.as_deref()),
+        encode_option_hex(gadget.descriptors.product.as_deref()),
+        encode_option_hex(gadget.descriptors.serial_number.as_deref()),
+        gadget.max_speed.as_ref().map_or("-", SpeedLimit::as_str),
+    );
+
+    for lun in &config.luns {
+        let _ = writeln!(
+            data,
+            "{} {} {} {} {} {} {} {} {}",
+            lun.lun,
+            u8::from(lun.attrs.cdrom),
+            u8::from(lun.attrs.ro),
+            u8::from(lun.attrs.removable),
+            u8::from(lun.attrs.nofua),
+            lun.dev,
+            lun.ino,
+            encode_hex(lun.attrs.inquiry_string.as_bytes()),
+            encode_hex(lun.file.as_os_str().as_bytes()),
+        );
+    }
+
+    data
+}
+
+fn parse_gadget_line(line: &str) -> Result<PersistedGadget> {
+    let fields = line.split(' ').collect::<Vec<_>>();
+    let [
+        stall,
+        id_vendor,
+        id_product,
+        bcd_device,
+        manufacturer,
+        product,
+        serial_number,
+        max_speed,
+    ] = fields[..]
+    else {
+        bail!("Expected 8 fields, but found {}", fields.len());
+    };
+
+    let max_speed = match max_speed {
+        "-" => None,
+        s if s == SpeedLimit::HighSpeed.as_str() => Some(SpeedLimit::HighSpeed),
+        s if s == SpeedLimit::FullSpeed.as_str() => Some(SpeedLimit::FullSpeed),
+        s => bail!("Invalid max speed: {s:?}"),
+    };
+
+    Ok(PersistedGadget {
+        stall: parse_bool(stall)?,
+        descriptors: DescriptorOverrides {
+            id_vendor: decode_option_u16(id_vendor)?,
+            id_product: decode_option_u16(id_product)?,
+            bcd_device: decode_option_u16(bcd_device)?,
+            manufacturer: decode_option_hex(manufacturer)?,
+            product: decode_option_hex(product)?,
+            serial_number: decode_option_hex(serial_number)?,
+        },
+        max_speed,
+    })
+}
+
+fn parse_lun_line(line: &str) -> Result<PersistedLun> {
+    let fields = line.split(' ').collect::<Vec<_>>();
+    let [
+        lun,
+        cdrom,
+        ro,
+        removable,
+        nofua,
+        dev,
+        ino,
+        inquiry_string,
+        file,
+    ] = fields[..]
+    else {
+        bail!("Expected 9 fields, but found {}", fields.len());
+    };
+
+    Ok(PersistedLun {
+        lun: lun
+            .parse()
+            .with_context(|| format!("Invalid LUN: {lun:?}"))?,
+        file: PathBuf::from(OsString::from_vec(decode_hex(file)?)),
+        dev: dev
+            .parse()
+            .with_context(|| format!("Invalid device: {dev:?}"))?,
+        ino: ino
+            .parse()
+            .with_context(|| format!("Invalid inode: {ino:?}"))?,
+        attrs: LunAttrs {
+            cdrom: parse_bool(cdrom)?,
+            ro: parse_bool(ro)?,
+            removable: parse_bool(removable)?,
+            nofua: parse_bool(nofua)?,
+            inquiry_string: String::from_utf8(decode_hex(inquiry_string)?)
+                .context("Inquiry string is not UTF-8")?,
+        },
+    })
+}
+
+/// Parse a serialized configuration.
+pub fn parse(data: &str) -> Result<PersistedConfig> {
+    let mut lines = data.lines();
+    if lines.next() != Some(HEADER) {
+        bail!("Unsupported configuration format");
+    }
+
+    let gadget = lines
+        .next()
+        .ok_or_else(|| anyhow!("Missing gadget settings"))
+        .and_then(|line| parse_gadget_line(line).context("Invalid line 2"))?;
+
+    let luns = lines
+        .enumerate()
+        .map(|(i, line)| parse_lun_line(line).with_context(|| format!("Invalid line {}", i + 3)))
+        .collect::<Result<_>>()?;
+
+    Ok(PersistedConfig { gadget, luns })
+}
+
+/// The file that the configuration is saved to. Its directory is opened up
+/// front so that the file can still be replaced after the daemon drops its
+/// privileges, even if the directory's ancestors, like `/data/adb`, are only
+/// accessible to root.
+pub struct ConfigFile {
+    path: PathBuf,
+    dir: Dir,
+    name: OsString,
+    temp_name: OsString,
+}
+
+impl ConfigFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Path has no file name: {path:?}"))?
+            .to_owned();
+        let parent = match path.parent() {
+            Some(p) if p != Path::new("") => p,
+            _ => Path::new("."),
+        };
+
+        let dir = Dir::open_ambient_dir(parent, ambient_authority())
+            .with_context(|| format!("Failed to open directory: {parent:?}"))?;
+
+        let mut temp_name = name.clone();
+        temp_name.push(".tmp");
+
+        Ok(Self {
+            path: path.to_owned(),
+            dir,
+            name,
+            temp_name,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the configuration. Returns None if the file does not exist.
+    pub fn load(&self) -> Result<Option<PersistedConfig>> {
+        let data = match self.dir.read_to_string(&self.name) {
+            Ok(d) => d,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read file: {:?}", self.path));
+            }
+        };
+
+        parse(&data)
+            .map(Some)
+            .with_context(|| format!("Invalid configuration: {:?}", self.path))
+    }
+
+    /// Atomically replace the configuration with already serialized data. The
+    /// data and the rename are both synced to disk so that a power loss cannot
+    /// leave behind an empty file.
+    pub fn save(&self, data: &str) -> Result<()> {
+        let temp_path = self.path.with_file_name(&self.temp_name);
+
+        let mut file = self
+            .dir
+            .create(&self.temp_name)
+            .with_context(|| format!("Failed to open file: {temp_path:?}"))?;
+        file.write_all(data.as_bytes())
+            .with_context(|| format!("Failed to write file: {temp_path:?}"))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to sync file: {temp_path:?}"))?;
+        drop(file);
+
+        self.dir
+            .rename(&self.temp_name, &self.dir, &self.name)
+            .with_context(|| format!("Failed to rename file: {temp_path:?} -> {:?}", self.path))?;
+
+        rustix::fs::fsync(&self.dir).with_context(|| {
+            format!(
+                "Failed to sync directory: {:?}",
+                self.path.with_file_name("")
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_config() -> PersistedConfig {
+        PersistedConfig {
+            gadget: PersistedGadget {
+                stall: false,
+                descriptors: DescriptorOverrides {
+                    id_vendor: Some(0x1d6b),
+                    id_product: Some(0x0104),
+                    bcd_device: None,
+                    manufacturer: Some("Linux Foundation".to_owned()),
+                    product: Some(String::new()),
+                    serial_number: None,
+                },
+                max_speed: Some(SpeedLimit::HighSpeed),
+            },
+            luns: vec![
+                PersistedLun {
+                    lun: 0,
+                    file: PathBuf::from("/data/media/0/My Images/installer.iso"),
+                    dev: 64769,
+                    ino: 1234567,
+                    attrs: LunAttrs {
+                        cdrom: true,
+                        ro: true,
+                        removable: true,
+                        nofua: false,
+                        inquiry_string: String::new(),
+                    },
+                },
+                PersistedLun {
+                    lun: 1,
+                    file: PathBuf::from(OsString::from_vec(b"/data/media/0/\\xff\n.img".to_vec())),
+                    dev: 1,
+                    ino: u64::MAX,
+                    attrs: LunAttrs {
+                        cdrom: false,
+                        ro: false,
+                        removable: false,
+                        nofua: true,
+                        inquiry_string: "Vendor  Product         0001".to_owned(),
+                    },
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = sample_config();
+        let data = serialize(&config);
+
+        assert_eq!(parse(&data).unwrap(), config);
+
+        let empty = PersistedConfig::default();
+        assert_eq!(parse(&serialize(&empty)).unwrap(), empty);
+    }
+
+    #[test]
+    fn decode_hex_fields() {
+        assert_eq!(decode_hex("-").unwrap(), b"");
+        assert_eq!(decode_hex("00ff7F").unwrap(), b"\x00\xff\x7f");
+        assert!(decode_hex("").is_err());
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex("zz").is_err());
+        assert!(decode_hex("+1").is_err());
+
+        assert_eq!(decode_option_hex("-").unwrap(), None);
+        assert_eq!(decode_option_hex("+").unwrap(), Some(String::new()));
+        assert_eq!(decode_option_hex("6d7364").unwrap(), Some("msd".to_owned()));
+        assert!(decode_option_hex("ff").is_err());
+
+        assert_eq!(decode_option_u16("-").unwrap(), None);
+        assert_eq!(decode_option_u16("1d6b").unwrap(), Some(0x1d6b));
+        assert!(decode_option_u16("+1d6").is_err());
+        assert!(decode_option_u16("10000").is_err());
+    }
+
+    #[test]
+    fn parse_gadget_settings() {
+        let data = format!("{HEADER}\n1 1d6b - 0001 + - 6d7364 full-speed\n");
+        let config = parse(&data).unwrap();
+
+        assert_eq!(
+            config.gadget,
+            PersistedGadget {
+                stall: true,
+                descriptors: DescriptorOverrides {
+                    id_vendor: Some(0x1d6b),
+                    id_product: None,
+                    bcd_device: Some(0x0001),
+                    manufacturer: Some(String::new()),
+                    product: None,
+                    serial_number: Some("msd".to_owned()),
+                },
+                max_speed: Some(SpeedLimit::FullSpeed),
+            },
+        );
+        assert!(config.luns.is_empty());
+    }
+
+    #[test]
+    fn reject_truncated() {
+        let data = serialize(&sample_config());
+
+        // Missing header, missing gadget line, and partial lines.
+        assert!(parse("").is_err());
+        assert!(parse(&format!("{HEADER}\n")).is_err());
+        for len in [HEADER.len() - 1, data.rfind(' ').unwrap()] {
+            assert!(parse(&data[..len]).is_err(), "Accepted: {:?}", &data[..len]);
+        }
+
+        let gadget_end = data.find('\n').unwrap() + 1;
+        let gadget_end = gadget_end + data[gadget_end..].find('\n').unwrap();
+        assert!(parse(&data[..gadget_end - 3]).is_err());
+    }
+
+    #[test]
+    fn reject_garbage() {
+        let gadget = "1 - - - - - - -";
+        let lun = "0 1 1 1 0 1 2 - 2f612e69736f";
+
+        assert!(parse(&format!("{HEADER}\n{gadget}\n{lun}\n")).is_ok());
+
+        for data in [
+            "garbage".to_owned(),
+            format!("msd-tool-config 2\n{gadget}\n"),
+            format!("{HEADER}\n2 - - - - - - -\n"),
+            format!("{HEADER}\n1 - - - - - - - -\n"),
+            format!("{HEADER}\n1 - - - - - - super-speed\n"),
+            format!("{HEADER}\n1 xyz - - - - - -\n"),
+            format!("{HEADER}\n{gadget}\n256 1 1 1 0 1 2 - 2f612e69736f\n"),
+            format!("{HEADER}\n{gadget}\n0 1 1 1 0 -1 2 - 2f612e69736f\n"),
+            format!("{HEADER}\n{gadget}\n0 1 1 1 0 1 2 - 2f612e69736\n"),
+            format!("{HEADER}\n{gadget}\n0 1 1 1 0 1 2 ff 2f612e69736f\n"),
+            format!("{HEADER}\n{gadget}\n{lun}\n\n"),
+            format!("{HEADER}\n{gadget}\n{lun} extra\n"),
+        ] {
+            assert!(parse(&data).is_err(), "Accepted: {data:?}");
+        }
+    }
+}
@@ -0,0 +1,103 @@
+// SPDX-FileCopyrightText: 2026 Andrew Gunnerson
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    io::{self, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{Context, Result, bail};
+use cap_std::{
+    ambient_authority,
+    fs::{Dir, OpenOptions},
+};
+use rustix::io::Errno;
+
+use crate::util;
+
+const POWER_ROOT: &str = "/sys/power";
+
+/// A kernel wake lock managed via `/sys/power/wake_lock`. Unlike wake locks
+/// held by file descriptors, these are not released when the process exits.
+pub struct WakeLock {
+    name: String,
+    dir: Dir,
+    held: AtomicBool,
+}
+
+impl WakeLock {
+    pub fn new(name: &str) -> Result<Self> {
+        // The kernel treats everything after the first whitespace character as
+        // the timeout.
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            bail!("Invalid wake lock name: {name:?}");
+        }
+
+        let dir = Dir::open_ambient_dir(POWER_ROOT, ambient_authority())
+            .and_then(|d| util::check_fs_magic(d, util::SYSFS_MAGIC))
+            .with_context(|| format!("Failed to open directory: {POWER_ROOT:?}"))?;
+
+        if !dir.exists("wake_lock") {
+            bail!("Kernel does not support wake locks via sysfs");
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            dir,
+            held: AtomicBool::new(false),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held.load(Ordering::SeqCst)
+    }
+
+    fn write_attr(&self, name: &str) -> io::Result<()> {
+        let mut file = self
+            .dir
+            .open_with(name, OpenOptions::new().write(true))
+            .and_then(|f| util::check_fs_magic(f, util::SYSFS_MAGIC))?;
+
+        file.write_all(self.name.as_bytes())
+    }
+
+    /// Acquire or release the wake lock. Returns whether the state changed.
+    pub fn set_held(&self, held: bool) -> Result<bool> {
+        if self.is_held() == held {
+            return Ok(false);
+        }
+
+        let attr = if held { "wake_lock" } else { "wake_unlock" };
+        self.write_attr(attr).with_context(|| {
+            format!(
+                "Failed to write file: {:?}",
+                Path::new(POWER_ROOT).join(attr)
+            )
+        })?;
+
+        self.held.store(held, Ordering::SeqCst);
+
+        Ok(true)
+    }
+
+    /// Release a wake lock with the same name that may have been left behind
+    /// by a previous process. Returns whether a wake lock was released.
+    pub fn release_stale(&self) -> Result<bool> {
+        match self.write_attr("wake_unlock") {
+            Ok(()) => Ok(true),
+            // The kernel returns EINVAL if the wake lock does not exist.
+            Err(e) if e.raw_os_error() == Some(Errno::INVAL.raw_os_error()) => Ok(false),
+            Err(e) => Err(e).with_context(|| {
+                format!(
+                    "Failed to write file: {:?}",
+                    Path::new(POWER_ROOT).join("wake_unlock"),
+                )
+            }),
+        }
+    }
+}
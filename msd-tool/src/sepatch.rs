@@ -12,6 +12,8 @@ use anyhow::{Context, Result, anyhow, bail};
 use clap::{Args, Parser};
 use sepatch::{PolicyDb, RuleAction};
 
+use crate::daemon::GADGET_HAL_DOMAINS;
+
 fn read_policy(path: &Path) -> Result<PolicyDb> {
     let data = fs::read(path).with_context(|| format!("Failed to open for reading: {path:?}"))?;
 
@@ -79,6 +81,7 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
     let n_target_type = "msd_app";
     let n_target_uffd_type = "msd_app_userfaultfd";
     let n_daemon_type = "msd_daemon";
+    let n_daemon_data_type = "msd_daemon_data_file";
 
     macro_rules! r {
         ($name:expr) => {{
@@ -114,6 +117,7 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
 
     let t_configfs = t!("configfs")?;
     let t_domain = t!("domain")?;
+    let t_file_type = t!("file_type")?;
     let t_fuse = t!("fuse")?;
     // We only support Android 11 and newer, which always has this type, but we
     // fall back to hal_usb_default because the only way to test sdcardfs in an
@@ -122,11 +126,20 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
         eprintln!("{e}; assuming old version of Android");
         t!("hal_usb_default")
     })?;
+    // Every domain that the daemon treats as the gadget HAL by default. The
+    // fallback for old Android versions is included too.
+    let mut gadget_hal_types = GADGET_HAL_DOMAINS
+        .iter()
+        .filter_map(|n| pdb.get_type_id(n))
+        .collect::<Vec<_>>();
+    if !gadget_hal_types.contains(&t_hal_usb_gadget_default) {
+        gadget_hal_types.push(t_hal_usb_gadget_default);
+    }
     // Allow us to run an arbitrary process as a fake "HAL" in the emulator.
-    let t_hal_usb_gadget_impl = t!("hal_usb_gadget_impl").or_else(|e| {
+    if let Err(e) = t!("hal_usb_gadget_impl") {
         eprintln!("{e}; allowing fake HAL running in su context for debugging");
-        t!("su")
-    })?;
+        gadget_hal_types.push(t!("su")?);
+    }
     let t_init = t!("init")?;
     let t_kernel = t!("kernel")?;
     let t_mediaprovider = t!("mediaprovider")?;
@@ -146,14 +159,35 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
         t!("exported2_system_prop")
     })?;
 
+    let c_blk_file = c!("blk_file")?;
+    let p_blk_file_getattr = p!(c_blk_file, "getattr")?;
+    let p_blk_file_ioctl = p!(c_blk_file, "ioctl")?;
+    let p_blk_file_lock = p!(c_blk_file, "lock")?;
+    let p_blk_file_open = p!(c_blk_file, "open")?;
+    let p_blk_file_read = p!(c_blk_file, "read")?;
+    let p_blk_file_write = p!(c_blk_file, "write")?;
+    let p_blk_file_watch = pdb.get_perm_id(c_blk_file, "watch");
+    let p_blk_file_watch_reads = pdb.get_perm_id(c_blk_file, "watch_reads");
+
     let c_capability = c!("capability")?;
     let p_capability_chown = p!(c_capability, "chown")?;
+    let p_capability_lease = p!(c_capability, "lease")?;
     let p_capability_setgid = p!(c_capability, "setgid")?;
     let p_capability_setuid = p!(c_capability, "setuid")?;
 
+    let c_capability2 = c!("capability2")?;
+    let p_capability2_block_suspend = p!(c_capability2, "block_suspend")?;
+
+    let c_chr_file = c!("chr_file")?;
+    let p_chr_file_ioctl = p!(c_chr_file, "ioctl")?;
+    let p_chr_file_open = p!(c_chr_file, "open")?;
+    let p_chr_file_read = p!(c_chr_file, "read")?;
+    let p_chr_file_write = p!(c_chr_file, "write")?;
+
     let c_dir = c!("dir")?;
     let p_dir_add_name = p!(c_dir, "add_name")?;
     let p_dir_create = p!(c_dir, "create")?;
+    let p_dir_getattr = p!(c_dir, "getattr")?;
     let p_dir_open = p!(c_dir, "open")?;
     let p_dir_read = p!(c_dir, "read")?;
     let p_dir_remove_name = p!(c_dir, "remove_name")?;
@@ -169,12 +203,22 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
     let p_file_create = p!(c_file, "create")?;
     let p_file_entrypoint = p!(c_file, "entrypoint")?;
     let p_file_execute = p!(c_file, "execute")?;
+    let p_file_execute_no_trans = p!(c_file, "execute_no_trans")?;
     let p_file_getattr = p!(c_file, "getattr")?;
+    let p_file_lock = p!(c_file, "lock")?;
     let p_file_map = p!(c_file, "map")?;
     let p_file_open = p!(c_file, "open")?;
     let p_file_read = p!(c_file, "read")?;
+    let p_file_rename = p!(c_file, "rename")?;
     let p_file_setattr = p!(c_file, "setattr")?;
+    let p_file_unlink = p!(c_file, "unlink")?;
     let p_file_write = p!(c_file, "write")?;
+    // Only present on Linux 5.3 and newer.
+    let p_file_watch = pdb.get_perm_id(c_file, "watch");
+    let p_file_watch_reads = pdb.get_perm_id(c_file, "watch_reads");
+
+    let c_filesystem = c!("filesystem")?;
+    let p_filesystem_getattr = p!(c_filesystem, "getattr")?;
 
     let c_lnk_file = c!("lnk_file")?;
     let p_lnk_file_create = p!(c_lnk_file, "create")?;
@@ -182,7 +226,14 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
     let p_lnk_file_setattr = p!(c_lnk_file, "setattr")?;
     let p_lnk_file_unlink = p!(c_lnk_file, "unlink")?;
 
+    let c_netlink_kobject_uevent_socket = c!("netlink_kobject_uevent_socket")?;
+    let p_netlink_kobject_uevent_socket_bind = p!(c_netlink_kobject_uevent_socket, "bind")?;
+    let p_netlink_kobject_uevent_socket_create = p!(c_netlink_kobject_uevent_socket, "create")?;
+    let p_netlink_kobject_uevent_socket_read = p!(c_netlink_kobject_uevent_socket, "read")?;
+    let p_netlink_kobject_uevent_socket_setopt = p!(c_netlink_kobject_uevent_socket, "setopt")?;
+
     let c_process = c!("process")?;
+    let p_process_getattr = p!(c_process, "getattr")?;
     let p_process_noatsecure = p!(c_process, "noatsecure")?;
     let p_process_rlimitinh = p!(c_process, "rlimitinh")?;
     let p_process_siginh = p!(c_process, "siginh")?;
@@ -255,6 +306,15 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
     pdb.set_attribute(t_daemon, t_domain, true)?;
     pdb.set_attribute(t_daemon, t_mlstrustedsubject, true)?;
 
+    // Create a new type for the daemon's saved configuration. The module's boot
+    // script labels the directory (persist::DEFAULT_PATH) with this type.
+
+    let t_daemon_data = pdb.create_type(n_daemon_data_type, false)?.0;
+    pdb.set_attribute(t_daemon_data, t_file_type, true)?;
+    if let Some(attr) = pdb.get_type_id("data_file_type") {
+        pdb.set_attribute(t_daemon_data, attr, true)?;
+    }
+
     // Setting the `domain` attribute isn't sufficient to grab many of the
     // "standard" rules. These are defined in the sepolicy source with a target
     // type of `self`, which means they were expanded at compile time. Since we
@@ -273,6 +333,15 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
         pdb.set_rule(t_daemon, t_system_file, c_file, perm, RuleAction::Allow);
     }
 
+    // Allow the daemon to spawn its watchdog process without a transition.
+    pdb.set_rule(
+        t_daemon,
+        t_system_file,
+        c_file,
+        p_file_execute_no_trans,
+        RuleAction::Allow,
+    );
+
     // Allow init to transition to the daemon domain.
     pdb.set_rule(
         t_init,
@@ -301,44 +370,59 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
         pdb.set_rule(t_daemon, t_daemon, c_capability, perm, RuleAction::Allow);
     }
 
+    // Allow the daemon to take leases on files owned by other users to check
+    // whether they are still being written to.
+    pdb.set_rule(
+        t_daemon,
+        t_daemon,
+        c_capability,
+        p_capability_lease,
+        RuleAction::Allow,
+    );
+
     // Allow the daemon to read the SELinux status.
     for perm in [p_file_open, p_file_read] {
         pdb.set_rule(t_daemon, t_selinuxfs, c_file, perm, RuleAction::Allow);
     }
 
-    // Allow the daemon to find (only) the USB gadget HAL in /proc.
-    pdb.set_rule(
-        t_daemon,
-        t_hal_usb_gadget_impl,
-        c_dir,
-        p_dir_search,
-        RuleAction::Allow,
-    );
+    // Allow the daemon to find (only) the USB gadget HAL in /proc and to send
+    // SIGSTOP/SIGCONT to it. Reading /proc/<pid>/attr/current requires process
+    // getattr. The HAL's cgroup is found via /proc/<pid>/cgroup, which is
+    // covered by the file rules.
     pdb.set_rule(t_daemon, t_domain, c_file, p_file_read, RuleAction::Deny);
-    pdb.set_rule(
-        t_daemon,
-        t_hal_usb_gadget_impl,
-        c_file,
-        p_file_read,
-        RuleAction::Allow,
-    );
-    pdb.set_rule(
-        t_daemon,
-        t_hal_usb_gadget_impl,
-        c_lnk_file,
-        p_lnk_file_read,
-        RuleAction::Allow,
-    );
+    for target in gadget_hal_types {
+        pdb.set_rule(t_daemon, target, c_dir, p_dir_search, RuleAction::Allow);
+        for perm in [p_file_open, p_file_read] {
+            pdb.set_rule(t_daemon, target, c_file, perm, RuleAction::Allow);
+        }
+        pdb.set_rule(
+            t_daemon,
+            target,
+            c_lnk_file,
+            p_lnk_file_read,
+            RuleAction::Allow,
+        );
+        for perm in [p_process_getattr, p_process_signal, p_process_sigstop] {
+            pdb.set_rule(t_daemon, target, c_process, perm, RuleAction::Allow);
+        }
+    }
 
-    // Allow the daemon to send SIGSTOP/SIGCONT to the USB gadget HAL.
-    for perm in [p_process_sigstop, p_process_signal] {
+    // Allow the daemon to freeze the USB gadget HAL's cgroup. This is optional
+    // because the daemon falls back to SIGSTOP when the freezer is unavailable.
+    if let Some(target) = pdb.get_type_id("cgroup_v2") {
         pdb.set_rule(
             t_daemon,
-            t_hal_usb_gadget_impl,
-            c_process,
-            perm,
+            target,
+            c_filesystem,
+            p_filesystem_getattr,
             RuleAction::Allow,
         );
+        for perm in [p_dir_open, p_dir_read, p_dir_search] {
+            pdb.set_rule(t_daemon, target, c_dir, perm, RuleAction::Allow);
+        }
+        for perm in [p_file_open, p_file_read, p_file_write] {
+            pdb.set_rule(t_daemon, target, c_file, perm, RuleAction::Allow);
+        }
     }
 
     // Allow the daemon to interact with configfs.
@@ -381,6 +465,101 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
         }
     }
 
+    // Allow the daemon to query the USB controller state and toggle the soft
+    // connect state in sysfs. The /sys/class/udc symlinks are labeled sysfs,
+    // but the targets may have a more specific label on some devices.
+    let t_sysfs = t!("sysfs")?;
+    for perm in [p_dir_open, p_dir_read, p_dir_search] {
+        pdb.set_rule(t_daemon, t_sysfs, c_dir, perm, RuleAction::Allow);
+    }
+    pdb.set_rule(
+        t_daemon,
+        t_sysfs,
+        c_lnk_file,
+        p_lnk_file_read,
+        RuleAction::Allow,
+    );
+    let t_sysfs_udc = pdb.get_type_id("sysfs_udc").unwrap_or(t_sysfs);
+    for perm in [p_dir_open, p_dir_read, p_dir_search] {
+        pdb.set_rule(t_daemon, t_sysfs_udc, c_dir, perm, RuleAction::Allow);
+    }
+    for perm in [p_file_getattr, p_file_open, p_file_read, p_file_write] {
+        pdb.set_rule(t_daemon, t_sysfs_udc, c_file, perm, RuleAction::Allow);
+    }
+
+    // Allow the daemon to read the I/O statistics of block devices via
+    // /sys/dev/block/<major>:<minor>/stat. This is limited to the sysfs types
+    // of virtual block devices, like loop devices, instead of all of sysfs.
+    let sysfs_block_types = ["sysfs_devices_block", "sysfs_loop", "sysfs_dm"]
+        .into_iter()
+        .filter_map(|n| pdb.get_type_id(n))
+        .collect::<Vec<_>>();
+    for target in sysfs_block_types {
+        for perm in [p_file_getattr, p_file_open, p_file_read] {
+            pdb.set_rule(t_daemon, target, c_file, perm, RuleAction::Allow);
+        }
+    }
+
+    // Allow the daemon to keep the device awake via /sys/power/wake_lock.
+    let t_sysfs_wake_lock = pdb.get_type_id("sysfs_wake_lock").unwrap_or(t_sysfs);
+    for perm in [p_file_open, p_file_write] {
+        pdb.set_rule(t_daemon, t_sysfs_wake_lock, c_file, perm, RuleAction::Allow);
+    }
+    pdb.set_rule(
+        t_daemon,
+        t_daemon,
+        c_capability2,
+        p_capability2_block_suspend,
+        RuleAction::Allow,
+    );
+
+    // Allow the daemon to monitor uevents for USB host connection changes.
+    for perm in [
+        p_netlink_kobject_uevent_socket_bind,
+        p_netlink_kobject_uevent_socket_create,
+        p_netlink_kobject_uevent_socket_read,
+        p_netlink_kobject_uevent_socket_setopt,
+    ] {
+        pdb.set_rule(
+            t_daemon,
+            t_daemon,
+            c_netlink_kobject_uevent_socket,
+            perm,
+            RuleAction::Allow,
+        );
+    }
+
+    // Allow the daemon to atomically replace its saved configuration. The
+    // directory is opened while the daemon is still root, but SELinux checks
+    // still apply when traversing /data and /data/adb.
+    for name in ["system_data_file", "adb_data_file"] {
+        if let Some(target) = pdb.get_type_id(name) {
+            pdb.set_rule(t_daemon, target, c_dir, p_dir_search, RuleAction::Allow);
+        }
+    }
+    for perm in [
+        p_dir_add_name,
+        p_dir_getattr,
+        p_dir_open,
+        p_dir_read,
+        p_dir_remove_name,
+        p_dir_search,
+        p_dir_write,
+    ] {
+        pdb.set_rule(t_daemon, t_daemon_data, c_dir, perm, RuleAction::Allow);
+    }
+    for perm in [
+        p_file_create,
+        p_file_getattr,
+        p_file_open,
+        p_file_read,
+        p_file_rename,
+        p_file_unlink,
+        p_file_write,
+    ] {
+        pdb.set_rule(t_daemon, t_daemon_data, c_file, perm, RuleAction::Allow);
+    }
+
     // Allow the daemon to read the external_storage.sdcardfs.enabled and
     // sys.usb.controller properties.
     for target in [t_storage_config_prop, t_usb_control_prop] {
@@ -421,7 +600,8 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
 
     let mut storage_types = vec![t_fuse];
     for name in [
-        // These are needed for older devices that use sdcardfs.
+        // These are needed for older devices that use sdcardfs. The latter is
+        // also needed for bypassing FUSE and restoring from /data/media.
         "sdcardfs",
         "media_rw_data_file",
         // For SD cards.
@@ -433,9 +613,129 @@ pub fn subcommand_sepatch(cli: &SepatchCli) -> Result<()> {
         }
     }
     for target in storage_types {
-        for perm in [p_file_getattr, p_file_read, p_file_open, p_file_write] {
+        for perm in [
+            p_file_getattr,
+            p_file_lock,
+            p_file_open,
+            p_file_read,
+            p_file_write,
+        ] {
+            pdb.set_rule(t_daemon, target, c_file, perm, RuleAction::Allow);
+        }
+
+        // Allow the daemon to detect host I/O via inotify.
+        for perm in [p_file_watch, p_file_watch_reads].into_iter().flatten() {
             pdb.set_rule(t_daemon, target, c_file, perm, RuleAction::Allow);
         }
+
+        // Allow the daemon to open the restore directory (--restore-dir) and
+        // the saved files within it.
+        for perm in [p_dir_getattr, p_dir_open, p_dir_read, p_dir_search] {
+            pdb.set_rule(t_daemon, target, c_dir, perm, RuleAction::Allow);
+        }
+        pdb.set_rule(
+            t_daemon,
+            target,
+            c_lnk_file,
+            p_lnk_file_read,
+            RuleAction::Allow,
+        );
+    }
+
+    // Allow the daemon to resolve restore directories specified via
+    // /storage, which contains symlinks and mount points for each user.
+    for name in ["storage_file", "mnt_user_file"] {
+        if let Some(target) = pdb.get_type_id(name) {
+            pdb.set_rule(t_daemon, target, c_dir, p_dir_search, RuleAction::Allow);
+            pdb.set_rule(
+                t_daemon,
+                target,
+                c_lnk_file,
+                p_lnk_file_read,
+                RuleAction::Allow,
+            );
+        }
+    }
+
+    // Allow the daemon to resolve paths to block devices in /dev/block.
+    for name in ["device", "block_device"] {
+        if let Some(target) = pdb.get_type_id(name) {
+            pdb.set_rule(t_daemon, target, c_dir, p_dir_search, RuleAction::Allow);
+        }
+    }
+
+    let watch_perms = [p_blk_file_watch, p_blk_file_watch_reads];
+    let blk_file_perms = [
+        p_blk_file_getattr,
+        p_blk_file_ioctl,
+        p_blk_file_lock,
+        p_blk_file_open,
+        p_blk_file_read,
+        p_blk_file_write,
+    ]
+    .into_iter()
+    .chain(watch_perms.into_iter().flatten())
+    .collect::<Vec<_>>();
+
+    // Allow the daemon to back LUNs with block devices sent by clients when
+    // --allow-block-devices is used, like SD cards and USB drives. Only block
+    // devices without a more specific type are allowed. This excludes the
+    // system partitions, which all have their own types, like
+    // system_block_device and userdata_block_device. vold_device is used for
+    // the nodes that vold creates for removable disks.
+    for name in ["block_device", "vold_device"] {
+        if let Some(target) = pdb.get_type_id(name) {
+            for &perm in &blk_file_perms {
+                pdb.set_rule(t_daemon, target, c_blk_file, perm, RuleAction::Allow);
+            }
+        }
+    }
+
+    // Allow the daemon to back LUNs with loop devices. This also allows the
+    // mass storage driver to reopen the loop devices.
+    if let (Some(t_loop_control_device), Some(t_loop_device)) = (
+        pdb.get_type_id("loop_control_device"),
+        pdb.get_type_id("loop_device"),
+    ) {
+        for perm in [
+            p_chr_file_ioctl,
+            p_chr_file_open,
+            p_chr_file_read,
+            p_chr_file_write,
+        ] {
+            pdb.set_rule(
+                t_daemon,
+                t_loop_control_device,
+                c_chr_file,
+                perm,
+                RuleAction::Allow,
+            );
+        }
+
+        for &perm in &blk_file_perms {
+            pdb.set_rule(t_daemon, t_loop_device, c_blk_file, perm, RuleAction::Allow);
+        }
+    }
+
+    // Allow the daemon to receive proxy file descriptors, which are opened by
+    // system_server from an appfuse mount. These can only be used with loop
+    // devices.
+    if let (Some(t_system_server), Some(t_app_fuse_file)) = (
+        pdb.get_type_id("system_server"),
+        pdb.get_type_id("app_fuse_file"),
+    ) {
+        pdb.set_rule(t_daemon, t_system_server, c_fd, p_fd_use, RuleAction::Allow);
+
+        // The file is reopened via procfs to lock it.
+        for perm in [
+            p_file_getattr,
+            p_file_lock,
+            p_file_open,
+            p_file_read,
+            p_file_write,
+        ] {
+            pdb.set_rule(t_daemon, t_app_fuse_file, c_file, perm, RuleAction::Allow);
+        }
     }
 
     // Allow the kernel to use the daemon's FD.
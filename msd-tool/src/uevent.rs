@@ -0,0 +1,206 @@
+// SPDX-FileCopyrightText: 2026 Andrew Gunnerson
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    collections::BTreeMap,
+    io,
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+};
+
+use rustix::{
+    io::Errno,
+    net::{
+        AddressFamily, RecvFlags, SocketFlags, SocketType,
+        netlink::{self, SocketAddrNetlink},
+        sockopt,
+    },
+};
+use tracing::debug;
+
+/// Multicast group that the kernel sends uevents to.
+const UEVENT_GROUP_KERNEL: u32 = 1;
+
+/// Maximum size of a uevent message. The kernel limits the environment to 2048
+/// bytes, but the header and separators add a bit more.
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+/// Size of the socket receive buffer. Bursts of uevents, like when a USB cable
+/// is plugged in, can overflow the default buffer.
+const RECV_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A kernel uevent.
+#[derive(Debug, Clone)]
+pub struct Uevent {
+    pub action: String,
+    pub devpath: String,
+    pub env: BTreeMap<String, String>,
+}
+
+impl Uevent {
+    /// Parse a raw uevent message of the form `<action>@<devpath>\0` followed
+    /// by `<key>=<value>\0` pairs. Returns None if the message is not a kernel
+    /// uevent, like the ones that libudev broadcasts.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut fields = data
+            .split(|b| *b == 0)
+            .filter(|f| !f.is_empty())
+            .map(|f| str::from_utf8(f).ok());
+
+        let header = fields.next()??;
+        let (action, devpath) = header.split_once('@')?;
+
+        let mut env = BTreeMap::new();
+
+        for field in fields {
+            let Some((key, value)) = field.and_then(|f| f.split_once('=')) else {
+                continue;
+            };
+
+            env.insert(key.to_owned(), value.to_owned());
+        }
+
+        Some(Self {
+            action: action.to_owned(),
+            devpath: devpath.to_owned(),
+            env,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.env.get(key).map(|v| v.as_str())
+    }
+
+    pub fn subsystem(&self) -> Option<&str> {
+        self.get("SUBSYSTEM")
+    }
+}
+
+/// A netlink socket for receiving kernel uevents.
+pub struct UeventSocket(OwnedFd);
+
+impl UeventSocket {
+    pub fn new() -> io::Result<Self> {
+        let fd = rustix::net::socket_with(
+            AddressFamily::NETLINK,
+            SocketType::DGRAM,
+            SocketFlags::CLOEXEC,
+            Some(netlink::KOBJECT_UEVENT),
+        )?;
+
+        // Exceeding net.core.rmem_max requires CAP_NET_ADMIN. Otherwise, the
+        // size is silently capped.
+        if let Err(e) = sockopt::set_socket_recv_buffer_size_force(&fd, RECV_BUFFER_SIZE) {
+            debug!("Failed to force socket receive buffer size: {e}");
+            sockopt::set_socket_recv_buffer_size(&fd, RECV_BUFFER_SIZE)?;
+        }
+
+        rustix::net::bind(&fd, &SocketAddrNetlink::new(0, UEVENT_GROUP_KERNEL))?;
+
+        Ok(Self(fd))
+    }
+
+    /// Receive the next pending uevent without blocking. Returns None if there
+    /// are no more pending uevents. Messages not sent by the kernel are
+    /// ignored. Fails with [`Errno::NOBUFS`] if uevents were dropped because
+    /// the receive buffer overflowed. The socket can still be used afterwards.
+    pub fn try_recv(&self) -> io::Result<Option<Uevent>> {
+        let mut buf = vec![0u8; UEVENT_BUFFER_SIZE];
+
+        loop {
+            let (n, _, addr) = match rustix::net::recvfrom(&self.0, &mut buf, RecvFlags::DONTWAIT) {
+                Ok(r) => r,
+                Err(Errno::WOULDBLOCK) => return Ok(None),
+                Err(Errno::INTR) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            // Only trust messages from the kernel, which always has port ID 0.
+            let sender = addr.and_then(|a| SocketAddrNetlink::try_from(a).ok());
+            if sender.is_none_or(|s| s.pid() != 0) {
+                debug!("Ignoring uevent from non-kernel sender: {sender:?}");
+                continue;
+            }
+
+            if let Some(uevent) = Uevent::parse(&buf[..n]) {
+                return Ok(Some(uevent));
+            }
+        }
+    }
+}
+
+impl AsFd for UeventSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_kernel_uevent() {
+        let data = b"change@/devices/virtual/android_usb/android0\0\
+            ACTION=change\0\
+            DEVPATH=/devices/virtual/android_usb/android0\0\
+            SUBSYSTEM=android_usb\0\
+            USB_STATE=CONFIGURED\0\
+            SEQNUM=1234\0";
+        let uevent = Uevent::parse(data).unwrap();
+
+        assert_eq!(uevent.action, "change");
+        assert_eq!(uevent.devpath, "/devices/virtual/android_usb/android0");
+        assert_eq!(uevent.subsystem(), Some("android_usb"));
+        assert_eq!(uevent.get("USB_STATE"), Some("CONFIGURED"));
+        assert_eq!(uevent.get("SEQNUM"), Some("1234"));
+        assert_eq!(uevent.get("MISSING"), None);
+        assert_eq!(uevent.env.len(), 5);
+    }
+
+    #[test]
+    fn parse_missing_terminators() {
+        let uevent =
+            Uevent::parse(b"remove@/devices/platform/a600000.dwc3/udc/a600000.dwc3").unwrap();
+        assert_eq!(uevent.action, "remove");
+        assert_eq!(
+            uevent.devpath,
+            "/devices/platform/a600000.dwc3/udc/a600000.dwc3"
+        );
+        assert!(uevent.env.is_empty());
+
+        let uevent = Uevent::parse(b"add@/foo\0SUBSYSTEM=udc\0KEY=value").unwrap();
+        assert_eq!(uevent.subsystem(), Some("udc"));
+        assert_eq!(uevent.get("KEY"), Some("value"));
+
+        // Empty fields from repeated terminators are skipped.
+        let uevent = Uevent::parse(b"\0\0add@/foo\0\0\0SUBSYSTEM=udc\0\0").unwrap();
+        assert_eq!(uevent.action, "add");
+        assert_eq!(uevent.subsystem(), Some("udc"));
+    }
+
+    #[test]
+    fn parse_malformed_fields() {
+        let uevent = Uevent::parse(
+            b"change@/foo\0NO_SEPARATOR\0BAD=\xff\xfe\0=empty_key\0EMPTY=\0A=b=c\0A2=x\0A2=y\0",
+        )
+        .unwrap();
+
+        assert_eq!(uevent.get("NO_SEPARATOR"), None);
+        assert_eq!(uevent.get("BAD"), None);
+        assert_eq!(uevent.get(""), Some("empty_key"));
+        assert_eq!(uevent.get("EMPTY"), Some(""));
+        assert_eq!(uevent.get("A"), Some("b=c"));
+        // The last occurrence of a duplicate key wins.
+        assert_eq!(uevent.get("A2"), Some("y"));
+    }
+
+    #[test]
+    fn reject_invalid_header() {
+        assert!(Uevent::parse(b"").is_none());
+        assert!(Uevent::parse(b"\0\0\0").is_none());
+        // libudev messages start with a magic header instead.
+        assert!(Uevent::parse(b"libudev\0\xfe\xed\xca\xfe").is_none());
+        assert!(Uevent::parse(b"change /foo\0SUBSYSTEM=udc\0").is_none());
+        assert!(Uevent::parse(b"change@/\xff\0SUBSYSTEM=udc\0").is_none());
+    }
+}
@@ -6,14 +6,17 @@ use std::{
     ffi::{OsStr, OsString},
     io::{self, IoSlice, Read, Write},
     os::{
-        fd::{AsRawFd, BorrowedFd},
+        fd::{AsFd, AsRawFd, BorrowedFd},
         unix::ffi::OsStringExt,
     },
     path::{Path, PathBuf},
 };
 
 use anyhow::{Context, Result, anyhow, bail};
-use cap_std::{ambient_authority, fs::Dir};
+use cap_std::{
+    ambient_authority,
+    fs::{Dir, File, OpenOptions},
+};
 use rustix::{
     fs::{AtFlags, Gid, Uid},
     io::Errno,
@@ -21,6 +24,8 @@ use rustix::{
 
 use crate::util;
 
+const UDC_CLASS_ROOT: &str = "/sys/class/udc";
+
 fn open_configfs_dir(path: &Path) -> Result<Dir> {
     Dir::open_ambient_dir(path, ambient_authority())
         .and_then(|d| util::check_fs_magic(d, util::CONFIGFS_MAGIC))
@@ -46,6 +51,15 @@ fn read_configfs_file(dir_path: &Path, dir: &Dir, path: &Path) -> Result<Vec<u8>
     Ok(buf)
 }
 
+/// Like [`read_configfs_file()`], but returns None if the file does not exist.
+fn read_optional_configfs_file(dir_path: &Path, dir: &Dir, path: &Path) -> Result<Option<Vec<u8>>> {
+    if !dir.exists(path) {
+        return Ok(None);
+    }
+
+    read_configfs_file(dir_path, dir, path).map(Some)
+}
+
 fn write_configfs_file(dir_path: &Path, dir: &Dir, path: &Path, bufs: &[IoSlice]) -> Result<()> {
     let mut file = dir
         .create(path)
@@ -118,6 +132,17 @@ fn chown_configfs_dir_to_rugid(dir_path: &Path, dir: &Dir, path: &Path) -> Resul
     Ok(())
 }
 
+/// Device descriptor fields that identify the USB gadget to the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GadgetDescriptors {
+    pub id_vendor: u16,
+    pub id_product: u16,
+    pub bcd_device: u16,
+    pub manufacturer: String,
+    pub product: String,
+    pub serial_number: String,
+}
+
 /// Configure a USB gadget via configfs.
 pub struct UsbGadget {
     root: PathBuf,
@@ -193,6 +218,121 @@ impl UsbGadget {
         Ok(())
     }
 
+    /// Get the USB controller that this gadget configuration is currently
+    /// associated with.
+    pub fn controller(&self) -> Result<Option<String>> {
+        let data = read_configfs_file(&self.root, &self.dir, Path::new("UDC"))?;
+        let data = String::from_utf8(data)
+            .with_context(|| format!("UDC is not valid UTF-8: {:?}", self.root.join("UDC")))?;
+        let id = data.trim_end_matches('\n');
+
+        if id.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(id.to_owned()))
+        }
+    }
+
+    fn read_string_attr(&self, path: &Path) -> Result<String> {
+        let mut data = read_configfs_file(&self.root, &self.dir, path)?;
+        pop_newline(&self.root, path, &mut data)?;
+
+        String::from_utf8(data)
+            .with_context(|| format!("configfs file is not UTF-8: {:?}", self.root.join(path)))
+    }
+
+    fn read_u16_attr(&self, path: &Path) -> Result<u16> {
+        let data = self.read_string_attr(path)?;
+        let Some(hex) = data.strip_prefix("0x") else {
+            bail!(
+                "configfs file is not hex: {:?}: {data:?}",
+                self.root.join(path)
+            );
+        };
+
+        u16::from_str_radix(hex, 16)
+            .with_context(|| format!("Invalid u16 in configfs file: {:?}", self.root.join(path)))
+    }
+
+    fn write_attr(&self, path: &Path, data: &[u8]) -> Result<()> {
+        write_configfs_file(
+            &self.root,
+            &self.dir,
+            path,
+            &[IoSlice::new(data), IoSlice::new(b"\n")],
+        )
+    }
+
+    /// Get the maximum speed that the gadget advertises to the host, like
+    /// `high-speed`. This is None on kernels that do not support limiting the
+    /// gadget's speed.
+    pub fn max_speed(&self) -> Result<Option<String>> {
+        let path = Path::new("max_speed");
+
+        if !self.dir.exists(path) {
+            return Ok(None);
+        }
+
+        self.read_string_attr(path).map(Some)
+    }
+
+    /// Set the maximum speed that the gadget advertises to the host. This can
+    /// only be done while the gadget is not associated with a USB controller.
+    pub fn set_max_speed(&self, speed: &str) -> Result<()> {
+        let path = Path::new("max_speed");
+
+        if !self.dir.exists(path) {
+            bail!("Kernel does not support limiting the gadget speed");
+        }
+
+        self.write_attr(path, speed.as_bytes())
+    }
+
+    /// Get the device descriptor fields. The strings are read from the en-US
+    /// (0x409) string table.
+    pub fn descriptors(&self) -> Result<GadgetDescriptors> {
+        let strings = Path::new("strings").join("0x409");
+
+        Ok(GadgetDescriptors {
+            id_vendor: self.read_u16_attr(Path::new("idVendor"))?,
+            id_product: self.read_u16_attr(Path::new("idProduct"))?,
+            bcd_device: self.read_u16_attr(Path::new("bcdDevice"))?,
+            manufacturer: self.read_string_attr(&strings.join("manufacturer"))?,
+            product: self.read_string_attr(&strings.join("product"))?,
+            serial_number: self.read_string_attr(&strings.join("serialnumber"))?,
+        })
+    }
+
+    /// Set the device descriptor fields. This takes effect the next time the
+    /// gadget is associated with a USB controller.
+    pub fn set_descriptors(&self, descriptors: &GadgetDescriptors) -> Result<()> {
+        let strings = Path::new("strings").join("0x409");
+
+        self.write_attr(
+            Path::new("idVendor"),
+            format!("{:#06x}", descriptors.id_vendor).as_bytes(),
+        )?;
+        self.write_attr(
+            Path::new("idProduct"),
+            format!("{:#06x}", descriptors.id_product).as_bytes(),
+        )?;
+        self.write_attr(
+            Path::new("bcdDevice"),
+            format!("{:#06x}", descriptors.bcd_device).as_bytes(),
+        )?;
+        self.write_attr(
+            &strings.join("manufacturer"),
+            descriptors.manufacturer.as_bytes(),
+        )?;
+        self.write_attr(&strings.join("product"), descriptors.product.as_bytes())?;
+        self.write_attr(
+            &strings.join("serialnumber"),
+            descriptors.serial_number.as_bytes(),
+        )?;
+
+        Ok(())
+    }
+
     /// Get the list of active gadget functions in the config.
     pub fn configs(&self) -> Result<BTreeMap<OsString, OsString>> {
         let (path, dir) = self.open_dir(&self.configs_rel_path())?;
@@ -333,6 +473,190 @@ impl UsbGadget {
     }
 }
 
+/// Query a USB device controller via sysfs.
+pub struct UsbController {
+    path: PathBuf,
+    dir: Dir,
+}
+
+impl UsbController {
+    pub fn new(id: &str) -> Result<Self> {
+        if id.is_empty() || id.contains('/') {
+            bail!("Invalid USB controller ID: {id:?}");
+        }
+
+        let path = Path::new(UDC_CLASS_ROOT).join(id);
+        let dir = Dir::open_ambient_dir(&path, ambient_authority())
+            .and_then(|d| util::check_fs_magic(d, util::SYSFS_MAGIC))
+            .with_context(|| format!("Failed to open directory: {path:?}"))?;
+
+        Ok(Self { path, dir })
+    }
+
+    fn read_attr(&self, name: &str) -> Result<String> {
+        let path = self.path.join(name);
+
+        let mut file = self
+            .dir
+            .open(name)
+            .and_then(|f| util::check_fs_magic(f, util::SYSFS_MAGIC))
+            .with_context(|| format!("Failed to open file for reading: {path:?}"))?;
+
+        let mut data = String::new();
+        file.read_to_string(&mut data)
+            .with_context(|| format!("Failed to read file: {path:?}"))?;
+
+        data.truncate(data.trim_end_matches('\n').len());
+
+        Ok(data)
+    }
+
+    fn write_attr(&self, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.path.join(name);
+
+        let mut file = self
+            .dir
+            .open_with(name, OpenOptions::new().write(true))
+            .and_then(|f| util::check_fs_magic(f, util::SYSFS_MAGIC))
+            .with_context(|| format!("Failed to open file for writing: {path:?}"))?;
+
+        file.write_all(data)
+            .with_context(|| format!("Failed to write file: {path:?}"))
+    }
+
+    /// Connect or disconnect the data pull-up resistor. This makes the host
+    /// see the device as being unplugged without affecting the gadget config.
+    /// Returns false if the controller does not support soft connect.
+    pub fn set_soft_connect(&self, connect: bool) -> Result<bool> {
+        let data: &[u8] = if connect {
+            b"connect\n"
+        } else {
+            b"disconnect\n"
+        };
+
+        match self.write_attr("soft_connect", data) {
+            Ok(()) => Ok(true),
+            Err(e)
+                if e.downcast_ref::<io::Error>().map(|ie| ie.kind())
+                    == Some(io::ErrorKind::NotFound) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get the USB device state, like `not attached`, `default`, `addressed`,
+    /// `configured`, or `suspended`.
+    pub fn state(&self) -> Result<String> {
+        self.read_attr("state")
+    }
+
+    /// Open the `state` attribute for watching. Changes to it are only
+    /// signaled via `sysfs_notify()`, not via uevents.
+    pub fn watch_state(&self) -> Result<UsbStateWatcher> {
+        let path = self.path.join("state");
+
+        let file = self
+            .dir
+            .open("state")
+            .and_then(|f| util::check_fs_magic(f, util::SYSFS_MAGIC))
+            .with_context(|| format!("Failed to open file for reading: {path:?}"))?;
+
+        Ok(UsbStateWatcher { path, file })
+    }
+
+    /// Get the speed negotiated with the host. This is `UNKNOWN` when no host
+    /// is connected.
+    pub fn current_speed(&self) -> Result<String> {
+        self.read_attr("current_speed")
+    }
+
+    /// Get the maximum speed supported by the controller.
+    pub fn maximum_speed(&self) -> Result<String> {
+        self.read_attr("maximum_speed")
+    }
+
+    /// Get whether the controller is an OTG controller.
+    pub fn is_otg(&self) -> Result<bool> {
+        let value = self.read_attr("is_otg")?;
+
+        match value.as_str() {
+            "1" => Ok(true),
+            "0" => Ok(false),
+            _ => bail!(
+                "sysfs file did not contain boolean: {:?}: {value:?}",
+                self.path.join("is_otg"),
+            ),
+        }
+    }
+}
+
+/// An open USB controller `state` attribute. The file descriptor can be polled
+/// for [`PollFlags::PRI`](rustix::event::PollFlags::PRI), which is signaled when
+/// the state changes.
+pub struct UsbStateWatcher {
+    path: PathBuf,
+    file: File,
+}
+
+impl UsbStateWatcher {
+    /// Get the current USB device state. This must be called after every
+    /// change notification to rearm it.
+    pub fn read(&self) -> Result<String> {
+        let mut buf = [0u8; 64];
+        let n = rustix::io::pread(&self.file, &mut buf, 0)
+            .with_context(|| format!("Failed to read file: {:?}", self.path))?;
+
+        let data = str::from_utf8(&buf[..n])
+            .with_context(|| format!("sysfs file is not UTF-8: {:?}", self.path))?;
+
+        Ok(data.trim_end_matches('\n').to_owned())
+    }
+}
+
+impl AsFd for UsbStateWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+/// Remove the trailing newline that configfs attributes end with.
+fn pop_newline(base_path: &Path, path: &Path, data: &mut Vec<u8>) -> Result<()> {
+    match data.pop() {
+        Some(b'\n') => return Ok(()),
+        Some(b) => data.push(b),
+        None => {}
+    }
+
+    bail!(
+        "configfs file did not end in newline: {:?}: {data:?}",
+        base_path.join(path),
+    );
+}
+
+/// Attributes of a mass storage LUN, excluding its backing file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LunAttrs {
+    pub cdrom: bool,
+    pub ro: bool,
+    /// Whether the host can eject the media.
+    pub removable: bool,
+    /// Whether to ignore the host's force unit access (FUA) flag.
+    pub nofua: bool,
+    /// Vendor (8 chars), product (16 chars), and revision (4 chars) reported
+    /// to the host. An empty string uses the kernel's default.
+    pub inquiry_string: String,
+}
+
+impl LunAttrs {
+    /// Kernel defaults for the attributes that older kernels and some vendor
+    /// gadget drivers do not have.
+    pub const DEFAULT_REMOVABLE: bool = true;
+    pub const DEFAULT_NOFUA: bool = false;
+    pub const DEFAULT_INQUIRY_STRING: &str = "";
+}
+
 /// Configure a mass storage USB gadget function.
 pub struct MassStorageFunction {
     path: PathBuf,
@@ -344,7 +668,8 @@ impl MassStorageFunction {
         Self { path, dir }
     }
 
-    /// Get the list of LUNs. The LUNs may or may not have associated files.
+    /// Get the sorted list of LUNs. The LUNs may or may not have associated
+    /// files.
     pub fn luns(&self) -> Result<Vec<u8>> {
         let mut result = vec![];
 
@@ -372,6 +697,8 @@ impl MassStorageFunction {
             result.push(n);
         }
 
+        result.sort_unstable();
+
         Ok(result)
     }
 
@@ -404,80 +731,128 @@ impl MassStorageFunction {
         }
     }
 
-    /// Get the configuration for an existing LUN. Returns the path, whether the
-    /// device is a CDROM, and whether the device is read-only.
-    pub fn get_lun(&self, lun: u8) -> Result<(Option<PathBuf>, bool, bool)> {
+    /// Get the configuration for an existing LUN. Returns the path of the
+    /// backing file, if any, and the LUN's attributes.
+    pub fn get_lun(&self, lun: u8) -> Result<(Option<PathBuf>, LunAttrs)> {
         let name = format!("lun.{lun}");
         let path = Path::new(&name);
 
         let file_path = path.join("file");
-        let cdrom_path = path.join("cdrom");
-        let ro_path = path.join("ro");
-
         let mut file = read_configfs_file(&self.path, &self.dir, &file_path)?;
-        let mut cdrom = read_configfs_file(&self.path, &self.dir, &cdrom_path)?;
-        let mut ro = read_configfs_file(&self.path, &self.dir, &ro_path)?;
-
-        fn pop_newline(base_path: &Path, path: &Path, data: &mut Vec<u8>) -> Result<()> {
-            match data.pop() {
-                Some(b'\n') => return Ok(()),
-                Some(b) => data.push(b),
-                None => {}
-            }
-
-            bail!(
-                "configfs file did not end in newline: {:?}: {data:?}",
-                base_path.join(path),
-            );
-        }
-
         if !file.is_empty() {
             pop_newline(&self.path, &file_path, &mut file)?;
         }
-        pop_newline(&self.path, &cdrom_path, &mut cdrom)?;
-        pop_newline(&self.path, &ro_path, &mut ro)?;
 
-        fn get_bool(base_path: &Path, path: &Path, data: &[u8]) -> Result<bool> {
-            match data {
+        let file = if file.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(OsString::from_vec(file)))
+        };
+
+        // Older kernels and some vendor gadget drivers lack the attributes
+        // besides cdrom and ro, so they are treated as having their defaults.
+        let read_bool = |attr: &str, default: Option<bool>| -> Result<bool> {
+            let attr_path = path.join(attr);
+            let Some(mut data) = read_optional_configfs_file(&self.path, &self.dir, &attr_path)?
+            else {
+                return default.ok_or_else(|| {
+                    anyhow!(
+                        "configfs file does not exist: {:?}",
+                        self.path.join(&attr_path),
+                    )
+                });
+            };
+            pop_newline(&self.path, &attr_path, &mut data)?;
+
+            match data.as_slice() {
                 b"1" => Ok(true),
                 b"0" => Ok(false),
                 _ => bail!(
                     "configfs file did not contain boolean: {:?}: {data:?}",
-                    base_path.join(path),
+                    self.path.join(&attr_path),
                 ),
             }
-        }
+        };
 
-        let file = if file.is_empty() {
-            None
-        } else {
-            Some(PathBuf::from(OsString::from_vec(file)))
+        let inquiry_string_path = path.join("inquiry_string");
+        let inquiry_string =
+            match read_optional_configfs_file(&self.path, &self.dir, &inquiry_string_path)? {
+                Some(mut data) => {
+                    pop_newline(&self.path, &inquiry_string_path, &mut data)?;
+
+                    String::from_utf8(data).with_context(|| {
+                        format!(
+                            "configfs file is not UTF-8: {:?}",
+                            self.path.join(&inquiry_string_path),
+                        )
+                    })?
+                }
+                None => LunAttrs::DEFAULT_INQUIRY_STRING.to_owned(),
+            };
+
+        let attrs = LunAttrs {
+            cdrom: read_bool("cdrom", None)?,
+            ro: read_bool("ro", None)?,
+            removable: read_bool("removable", Some(LunAttrs::DEFAULT_REMOVABLE))?,
+            nofua: read_bool("nofua", Some(LunAttrs::DEFAULT_NOFUA))?,
+            inquiry_string,
         };
-        let cdrom = get_bool(&self.path, &cdrom_path, &cdrom)?;
-        let ro = get_bool(&self.path, &ro_path, &ro)?;
 
-        Ok((file, cdrom, ro))
+        Ok((file, attrs))
     }
 
     /// Set the configuration for a LUN. This can only be done if a LUN is newly
-    /// created and does not have an associated file set yet.
-    pub fn set_lun(&self, lun: u8, fd: BorrowedFd, cdrom: bool, ro: bool) -> Result<()> {
+    /// created or ejected and does not have an associated file set yet.
+    pub fn set_lun(&self, lun: u8, fd: BorrowedFd, attrs: &LunAttrs) -> Result<()> {
         let name = format!("lun.{lun}");
         let path = Path::new(&name);
 
-        // The file path must be written last.
-        write_configfs_file(
-            &self.path,
-            &self.dir,
-            &path.join("cdrom"),
-            &[IoSlice::new(if cdrom { b"1\n" } else { b"0\n" })],
+        let bool_slice = |value: bool| IoSlice::new(if value { b"1\n" } else { b"0\n" });
+        let write_bool = |attr: &str, value: bool| {
+            write_configfs_file(
+                &self.path,
+                &self.dir,
+                &path.join(attr),
+                &[bool_slice(value)],
+            )
+        };
+
+        // Attributes that may not exist are only written if they differ from
+        // the default or if the LUN has them. The latter is needed to reset a
+        // reused LUN's attribute to the default.
+        let write_optional = |attr: &str, is_default: bool, bufs: &[IoSlice]| {
+            let attr_path = path.join(attr);
+
+            if is_default && !self.dir.exists(&attr_path) {
+                return Ok(());
+            }
+
+            write_configfs_file(&self.path, &self.dir, &attr_path, bufs)
+        };
+
+        write_bool("cdrom", attrs.cdrom)?;
+        write_bool("ro", attrs.ro)?;
+        write_optional(
+            "removable",
+            attrs.removable == LunAttrs::DEFAULT_REMOVABLE,
+            &[bool_slice(attrs.removable)],
         )?;
-        write_configfs_file(
-            &self.path,
-            &self.dir,
-            &path.join("ro"),
-            &[IoSlice::new(if ro { b"1\n" } else { b"0\n" })],
+        write_optional(
+            "nofua",
+            attrs.nofua == LunAttrs::DEFAULT_NOFUA,
+            &[bool_slice(attrs.nofua)],
         )?;
+        // An empty string restores the kernel's default.
+        write_optional(
+            "inquiry_string",
+            attrs.inquiry_string == LunAttrs::DEFAULT_INQUIRY_STRING,
+            &[
+                IoSlice::new(attrs.inquiry_string.as_bytes()),
+                IoSlice::new(b"\n"),
+            ],
+        )?;
+
+        // The file path must be written last.
         write_configfs_file(
             &self.path,
             &self.dir,
@@ -495,6 +870,32 @@ impl MassStorageFunction {
         Ok(())
     }
 
+    /// Set whether the function is allowed to halt bulk endpoints. This can
+    /// only be done while the function is not part of a config.
+    pub fn set_stall(&self, stall: bool) -> Result<()> {
+        write_configfs_file(
+            &self.path,
+            &self.dir,
+            Path::new("stall"),
+            &[IoSlice::new(if stall { b"1\n" } else { b"0\n" })],
+        )
+    }
+
+    /// Eject the media from a LUN, even if the host prevented medium removal.
+    /// Afterwards, a new file can be set with [`Self::set_lun`] while the
+    /// function is in use. On kernels without `forced_eject`, this falls back
+    /// to clearing the file, which fails if the host locked the medium.
+    pub fn forced_eject(&self, lun: u8) -> Result<()> {
+        let name = format!("lun.{lun}");
+        let path = Path::new(&name).join("forced_eject");
+
+        if !self.dir.exists(&path) {
+            return self.clear_lun(lun);
+        }
+
+        write_configfs_file(&self.path, &self.dir, &path, &[IoSlice::new(b"1\n")])
+    }
+
     /// Clear the configuration for a LUN.
     pub fn clear_lun(&self, lun: u8) -> Result<()> {
         let name = format!("lun.{lun}");
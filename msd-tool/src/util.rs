@@ -3,24 +3,36 @@
 
 use std::{
     ffi::OsString,
-    io,
-    os::fd::{AsFd, BorrowedFd, OwnedFd},
-    path::PathBuf,
+    fs::File,
+    io::{self, Read, Write},
+    ops::Add,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
 };
 
 use cap_std::{
     ambient_authority,
-    fs::{Dir, ReadDir},
+    fs::{Dir, OpenOptions, ReadDir},
 };
 use rustix::{
+    fs::{FileType, FlockOperation},
     io::Errno,
+    ioctl::{self, Getter, Opcode, opcode},
     process::{Pid, Signal},
+    time::ClockId,
 };
 use tracing::debug;
 
+pub const CGROUP2_SUPER_MAGIC: u32 = 0x63677270;
 pub const CONFIGFS_MAGIC: u32 = 0x62656570;
+pub const FUSE_SUPER_MAGIC: u32 = 0x65735546;
 pub const PROC_SUPER_MAGIC: u32 = 0x9fa0;
 pub const SELINUX_MAGIC: u32 = 0xf97cff8c;
+pub const SYSFS_MAGIC: u32 = 0x62656572;
+
+const CGROUP2_ROOT: &str = "/sys/fs/cgroup";
 
 /// Ensure that the fd refers to a file that lives on the specified type of
 /// filesystem. This prevents reading a "fake" file backed by FUSE or similar.
@@ -38,6 +50,111 @@ pub fn check_fs_magic<T: AsFd>(fd: T, magic: u32) -> io::Result<T> {
     Ok(fd)
 }
 
+/// Get the device and inode numbers that identify a file. For block devices,
+/// the device number of the block device itself is used instead, so that
+/// different device nodes for the same block device are treated as the same
+/// file. Inode 0 is never valid for regular files.
+#[allow(clippy::useless_conversion)] // Narrower on some architectures.
+pub fn file_id(fd: BorrowedFd) -> io::Result<(u64, u64)> {
+    let stat = rustix::fs::fstat(fd)?;
+
+    if FileType::from_raw_mode(stat.st_mode) == FileType::BlockDevice {
+        Ok((stat.st_rdev.into(), 0))
+    } else {
+        Ok((stat.st_dev.into(), stat.st_ino.into()))
+    }
+}
+
+/// An advisory lock on an open file description, which is released when
+/// dropped. The lock is shared by all fds that refer to the same open file
+/// description.
+#[derive(Debug)]
+pub struct FileLock(OwnedFd);
+
+impl FileLock {
+    /// Lock the file without blocking. Fails with [`Errno::WOULDBLOCK`] if
+    /// another open file description holds a conflicting lock. The file is
+    /// reopened via procfs so that the lock is held by a new open file
+    /// description that is not shared with whoever else has `fd`.
+    pub fn new(fd: BorrowedFd, exclusive: bool) -> io::Result<Self> {
+        let fd = OwnedFd::from(File::open(format!("/proc/self/fd/{}", fd.as_raw_fd()))?);
+        let operation = if exclusive {
+            FlockOperation::NonBlockingLockExclusive
+        } else {
+            FlockOperation::NonBlockingLockShared
+        };
+
+        rustix::fs::flock(&fd, operation)?;
+
+        Ok(Self(fd))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = rustix::fs::flock(&self.0, FlockOperation::Unlock);
+    }
+}
+
+/// A point in time on `CLOCK_BOOTTIME`. Unlike [`Instant`], which uses
+/// `CLOCK_MONOTONIC`, this keeps advancing while the device is suspended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BootInstant(Duration);
+
+impl BootInstant {
+    pub fn now() -> Self {
+        let ts = rustix::time::clock_gettime(ClockId::Boottime);
+
+        Self(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().0.saturating_sub(self.0)
+    }
+}
+
+impl Add<Duration> for BootInstant {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self {
+        Self(self.0 + rhs)
+    }
+}
+
+/// Get the size of a block device in bytes.
+pub fn block_device_size(fd: BorrowedFd) -> io::Result<u64> {
+    // _IOR(0x12, 114, size_t), but the kernel always writes a u64.
+    const BLKGETSIZE64: Opcode = opcode::read::<usize>(0x12, 114);
+
+    // SAFETY: BLKGETSIZE64 is a getter opcode that gets a u64.
+    let size = unsafe { ioctl::ioctl(fd, Getter::<BLKGETSIZE64, u64>::new())? };
+
+    Ok(size)
+}
+
+/// Check if any process has the file open for writing. This is done by taking
+/// a read lease on a separate read-only open file description, which the
+/// kernel refuses if the file has any writers, including the caller itself.
+/// The lease is released immediately. Taking a lease on a file owned by
+/// another user requires `CAP_LEASE`.
+pub fn has_writers(fd: BorrowedFd) -> io::Result<bool> {
+    let file = File::open(format!("/proc/self/fd/{}", fd.as_raw_fd()))?;
+
+    // SAFETY: F_SETLEASE takes an integer argument.
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLEASE, libc::F_RDLCK) };
+    if ret == -1 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EAGAIN) {
+            return Ok(true);
+        }
+
+        return Err(e);
+    }
+
+    // Closing the file releases the lease.
+    Ok(false)
+}
+
 // The NDK has the pidfd constants, but the libc crate doesn't yet, so rustix
 // doesn't enable the functionality for Android.
 
@@ -85,8 +202,75 @@ pub fn pidfd_send_signal<Fd: AsFd>(pidfd: Fd, sig: Signal) -> Result<(), Errno>
     }
 }
 
-/// Iterate through all PIDs, yielding a pidfd and the process executable name.
-/// Kernel threads, PIDs that disappear during procfs traversal, and PIDs that
+/// Parse an SELinux context, which may be followed by a NUL terminator or a
+/// newline.
+fn parse_label(mut data: Vec<u8>) -> Option<String> {
+    while data.last().is_some_and(|b| *b == b'\0' || *b == b'\n') {
+        data.pop();
+    }
+
+    String::from_utf8(data).ok()
+}
+
+/// Get the SELinux context of a unix socket's peer via `SO_PEERSEC`. This is
+/// the context that the peer had when it connected, so unlike reading it from
+/// procfs, it is not affected by PID reuse. Returns None if the context is not
+/// valid UTF-8.
+pub fn socket_peer_label(fd: BorrowedFd) -> io::Result<Option<String>> {
+    let mut buf = vec![0u8; 256];
+
+    loop {
+        let mut len = buf.len() as libc::socklen_t;
+
+        // SAFETY: The buffer is valid for len bytes.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERSEC,
+                buf.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        if ret == -1 {
+            let e = io::Error::last_os_error();
+
+            // The kernel reports the required size if the buffer is too small.
+            if e.raw_os_error() == Some(libc::ERANGE) && len as usize > buf.len() {
+                buf.resize(len as usize, 0);
+                continue;
+            }
+
+            return Err(e);
+        }
+
+        buf.truncate(len as usize);
+
+        return Ok(parse_label(buf));
+    }
+}
+
+/// A running process found by [`ProcessIter`].
+#[derive(Debug)]
+pub struct Process {
+    pub pid: Pid,
+    pub pidfd: OwnedFd,
+    /// The executable name or None if it could not be read.
+    pub name: Option<OsString>,
+    /// The SELinux context or None if it could not be read.
+    pub label: Option<String>,
+}
+
+impl Process {
+    /// Get the SELinux domain (type) from the process' context.
+    pub fn domain(&self) -> Option<&str> {
+        self.label.as_deref().and_then(|l| l.split(':').nth(2))
+    }
+}
+
+/// Iterate through all PIDs, yielding the PID, a pidfd, the process executable
+/// name, and the SELinux context. Kernel threads, PIDs that disappear during
+/// procfs traversal, and PIDs whose executable name and SELinux context both
 /// cannot be read due to permissions are ignored.
 pub struct ProcessIter {
     dir: Dir,
@@ -104,7 +288,7 @@ impl ProcessIter {
 }
 
 impl Iterator for ProcessIter {
-    type Item = io::Result<(OwnedFd, OsString)>;
+    type Item = io::Result<Process>;
 
     fn next(&mut self) -> Option<Self::Item> {
         for entry in &mut self.entries {
@@ -133,28 +317,47 @@ impl Iterator for ProcessIter {
                 Err(e) => return Some(Err(e.into())),
             };
 
+            // The SELinux context is read first because it identifies the
+            // process even if the executable path cannot be read.
             let mut path = PathBuf::from(entry.file_name());
-            path.push("exe");
+            path.push("attr");
+            path.push("current");
 
-            // ENOENT in this case is not due to disappearing PIDs, but rather
-            // PIDs being kernel threads, which don't have a corresponding
-            // executable.
-            let target = match self.dir.read_link_contents(&path) {
-                Ok(c) => c,
+            let label = match self.dir.read(&path) {
+                Ok(data) => parse_label(data),
                 Err(e)
                     if e.kind() == io::ErrorKind::NotFound
-                        || e.kind() == io::ErrorKind::PermissionDenied =>
+                        || e.kind() == io::ErrorKind::PermissionDenied
+                        || e.kind() == io::ErrorKind::InvalidInput =>
                 {
-                    continue;
+                    None
                 }
                 Err(e) => return Some(Err(e)),
             };
 
-            let Some(file_name) = target.file_name() else {
-                continue;
+            let mut path = PathBuf::from(entry.file_name());
+            path.push("exe");
+
+            // ENOENT in this case is not due to disappearing PIDs, but rather
+            // PIDs being kernel threads, which don't have a corresponding
+            // executable.
+            let name = match self.dir.read_link_contents(&path) {
+                Ok(c) => c.file_name().map(|n| n.to_owned()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => None,
+                Err(e) => return Some(Err(e)),
             };
 
-            return Some(Ok((pidfd, file_name.to_owned())));
+            if name.is_none() && label.is_none() {
+                continue;
+            }
+
+            return Some(Ok(Process {
+                pid,
+                pidfd,
+                name,
+                label,
+            }));
         }
 
         None
@@ -188,6 +391,175 @@ impl Drop for ProcessStopper {
     }
 }
 
+/// Get the path of the cgroup v2 group that a process belongs to, relative to
+/// the cgroup v2 mount point. Returns None if the process is not part of a
+/// cgroup v2 hierarchy.
+pub fn process_cgroup(pid: Pid) -> io::Result<Option<PathBuf>> {
+    let path = format!("/proc/{}/cgroup", pid.as_raw_nonzero());
+    let mut file = File::open(path).and_then(|f| check_fs_magic(f, PROC_SUPER_MAGIC))?;
+
+    let mut data = String::new();
+    file.read_to_string(&mut data)?;
+
+    // The unified hierarchy always has a hierarchy ID of 0 and no controllers.
+    for line in data.lines() {
+        if let Some(group) = line.strip_prefix("0::") {
+            let group = group.trim_start_matches('/');
+            return Ok(Some(PathBuf::from(group)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// A cgroup v2 group.
+pub struct Cgroup {
+    path: PathBuf,
+    dir: Dir,
+}
+
+impl Cgroup {
+    /// Open a cgroup by its path relative to the cgroup v2 mount point.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let root = Dir::open_ambient_dir(CGROUP2_ROOT, ambient_authority())
+            .and_then(|d| check_fs_magic(d, CGROUP2_SUPER_MAGIC))?;
+        let dir = if path.as_os_str().is_empty() {
+            root
+        } else {
+            root.open_dir(path)
+                .and_then(|d| check_fs_magic(d, CGROUP2_SUPER_MAGIC))?
+        };
+
+        Ok(Self {
+            path: Path::new(CGROUP2_ROOT).join(path),
+            dir,
+        })
+    }
+
+    fn read_file(&self, name: &str) -> io::Result<String> {
+        let mut file = self
+            .dir
+            .open(name)
+            .and_then(|f| check_fs_magic(f, CGROUP2_SUPER_MAGIC))?;
+
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+
+        Ok(data)
+    }
+
+    fn write_file(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let mut file = self
+            .dir
+            .open_with(name, OpenOptions::new().write(true))
+            .and_then(|f| check_fs_magic(f, CGROUP2_SUPER_MAGIC))?;
+
+        file.write_all(data)
+    }
+
+    /// Get the list of PIDs in this cgroup. This does not include descendant
+    /// cgroups.
+    pub fn procs(&self) -> io::Result<Vec<Pid>> {
+        self.read_file("cgroup.procs")?
+            .lines()
+            .map(|line| {
+                line.parse::<i32>()
+                    .ok()
+                    .and_then(Pid::from_raw)
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, format!("Invalid PID: {line:?}"))
+                    })
+            })
+            .collect()
+    }
+
+    /// Get the number of descendant cgroups, which are frozen along with this
+    /// cgroup.
+    pub fn nr_descendants(&self) -> io::Result<u64> {
+        for line in self.read_file("cgroup.stat")?.lines() {
+            if let Some(value) = line.strip_prefix("nr_descendants ") {
+                return value.parse().map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid nr_descendants: {value:?}: {e}"),
+                    )
+                });
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "cgroup.stat has no nr_descendants key",
+        ))
+    }
+
+    /// Request that the cgroup be frozen or thawed. Freezing happens
+    /// asynchronously. Use [`Self::is_frozen()`] to check the current state.
+    pub fn set_frozen(&self, frozen: bool) -> io::Result<()> {
+        self.write_file("cgroup.freeze", if frozen { b"1\n" } else { b"0\n" })
+    }
+
+    /// Check if the cgroup and all of its descendants are fully frozen.
+    pub fn is_frozen(&self) -> io::Result<bool> {
+        for line in self.read_file("cgroup.events")?.lines() {
+            if let Some(value) = line.strip_prefix("frozen ") {
+                return Ok(value == "1");
+            }
+        }
+
+        // The root cgroup cannot be frozen and has no frozen key.
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cgroup does not support the freezer",
+        ))
+    }
+}
+
+/// Freeze a cgroup v2 group when constructed and thaw it when dropped. Unlike
+/// SIGSTOP, this is not observable by the parent process and the tasks are only
+/// frozen once they're no longer in the middle of a syscall.
+pub struct CgroupFreezer(Cgroup);
+
+impl CgroupFreezer {
+    /// Freeze the cgroup and wait up to `timeout` for all tasks to be frozen.
+    pub fn new(cgroup: Cgroup, timeout: Duration) -> io::Result<Self> {
+        let result = Self(cgroup);
+        result.freeze(timeout)?;
+        Ok(result)
+    }
+
+    pub fn freeze(&self, timeout: Duration) -> io::Result<()> {
+        debug!("Freezing cgroup {:?}", self.0.path);
+        self.0.set_frozen(true)?;
+
+        let start = Instant::now();
+
+        while !self.0.is_frozen()? {
+            if start.elapsed() >= timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Timed out waiting for cgroup to freeze: {:?}", self.0.path),
+                ));
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Ok(())
+    }
+
+    pub fn thaw(&self) -> io::Result<()> {
+        debug!("Thawing cgroup {:?}", self.0.path);
+        self.0.set_frozen(false)
+    }
+}
+
+impl Drop for CgroupFreezer {
+    fn drop(&mut self) {
+        let _ = self.thaw();
+    }
+}
+
 pub fn fd_get_label(fd: BorrowedFd) -> io::Result<String> {
     const NAME: &str = "security.selinux";
 